@@ -0,0 +1,55 @@
+// Builds the C wrapper around libkeyfinder when the `libkeyfinder` feature is enabled.
+//
+// If the library cannot be found via pkg-config we only print a warning and the crate
+// falls back to the native key detector. The wrapper only uses the public API of
+// libkeyfinder 2 (`KeyFinder`, `AudioData`, `Workspace` and the accessors of
+// `Chromagram`), no internal headers.
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(has_libkeyfinder)");
+
+    #[cfg(feature = "libkeyfinder")]
+    libkeyfinder::build();
+}
+
+#[cfg(feature = "libkeyfinder")]
+mod libkeyfinder {
+    pub fn build() {
+        println!("cargo:rerun-if-changed=native/kfwrapper.cpp");
+
+        // find the library, but only emit the linker flags after the wrapper so the
+        // static wrapper comes first on the link line
+        let library = match pkg_config::Config::new()
+            .cargo_metadata(false)
+            .probe("libkeyfinder")
+        {
+            Ok(library) => library,
+            Err(e) => {
+                println!(
+                    "cargo:warning=libkeyfinder not found, falling back to the native key detector: {}",
+                    e.to_string().replace('\n', " ")
+                );
+                return;
+            }
+        };
+
+        let mut wrapper = cc::Build::new();
+        wrapper
+            .cpp(true)
+            .flag_if_supported("-std=c++11")
+            .file("native/kfwrapper.cpp");
+        for path in &library.include_paths {
+            wrapper.include(path);
+        }
+        wrapper.compile("kfwrapper");
+
+        for path in &library.link_paths {
+            println!("cargo:rustc-link-search=native={}", path.display());
+        }
+        for lib in &library.libs {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+
+        println!("cargo:rustc-cfg=has_libkeyfinder");
+    }
+}
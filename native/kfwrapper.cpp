@@ -0,0 +1,152 @@
+// A small C wrapper around libkeyfinder, so the Rust side does not have to deal
+// with the C++ ABI. Built by `build.rs` when the `libkeyfinder` feature is enabled.
+
+#include <cstdint>
+#include <exception>
+#include <vector>
+
+#include <keyfinder/keyfinder.h>
+
+// This is a shared instance that contains functions & data used by all instances of
+// a keyfinder object is used read-only
+static KeyFinder::KeyFinder kfwrapper_shared_keyfinder;
+
+// creates a new instance of the keyfinder state
+extern "C"
+void* kfwrapper__init_audio_data(uint32_t frame_rate) {
+    const auto a = new KeyFinder::AudioData();
+    a->setFrameRate(frame_rate);
+    a->setChannels(1);
+    return a;
+}
+
+// changes the frame rate of the audio data
+extern "C"
+void kfwrapper__set_frame_rate(void* audio_data, uint32_t frame_rate) {
+    ((KeyFinder::AudioData*)audio_data)->setFrameRate(frame_rate);
+}
+
+// changes the number of interleaved channels in the audio data
+extern "C"
+void kfwrapper__set_channels(void* audio_data, uint32_t channels) {
+    ((KeyFinder::AudioData*)audio_data)->setChannels(channels);
+}
+
+// destroys and cleans up after the keyfinder.
+extern "C"
+void kfwrapper__destroy_audio_data(void* audio_data) {
+    delete ((KeyFinder::AudioData*)audio_data);
+}
+
+// The main processing function: takes a bunch of samples and adds it to the keyfinder audiodata
+extern "C"
+void kfwrapper__add_to_samples(void* audio_data, const float* data, uint64_t data_size) {
+    auto a = (KeyFinder::AudioData*)audio_data;
+
+    // append after the samples we already have
+    const auto offset = a->getSampleCount();
+    a->addToSampleCount(data_size);
+
+    // Copy your audio into the object
+    for (uint64_t i = 0; i < data_size; i++) {
+        a->setSample(offset + i, data[i]);
+    }
+}
+
+// After all samples are added we can use this function to get the key from LibKeyFinder
+extern "C"
+int32_t kfwrapper__key_of_audio(void* audio_data) {
+    auto a = (KeyFinder::AudioData*)audio_data;
+
+    try {
+        KeyFinder::key_t key = kfwrapper_shared_keyfinder.keyOfAudio(*a);
+        return (int32_t)key;
+    } catch (const std::exception&) {
+        // libkeyfinder throws on empty or invalid audio, report it as silence
+        return (int32_t)KeyFinder::SILENCE;
+    }
+}
+
+// creates a new workspace that collects the chromagram of a stream
+extern "C"
+void* kfwrapper__init_workspace() {
+    return new KeyFinder::Workspace();
+}
+
+// destroys the workspace
+extern "C"
+void kfwrapper__destroy_workspace(void* workspace) {
+    delete ((KeyFinder::Workspace*)workspace);
+}
+
+// Adds the chromagram of the audio data to the workspace. The audio data is copied, so it
+// can be reused for the next block of samples.
+extern "C"
+void kfwrapper__progressive_chromagram(void* audio_data, void* workspace) {
+    auto a = (KeyFinder::AudioData*)audio_data;
+    auto w = (KeyFinder::Workspace*)workspace;
+
+    try {
+        kfwrapper_shared_keyfinder.progressiveChromagram(*a, *w);
+    } catch (const std::exception&) {
+        // an empty block has nothing to add
+    }
+}
+
+// Flushes the audio still buffered in the workspace into its chromagram
+extern "C"
+void kfwrapper__final_chromagram(void* workspace) {
+    auto w = (KeyFinder::Workspace*)workspace;
+
+    try {
+        kfwrapper_shared_keyfinder.finalChromagram(*w);
+    } catch (const std::exception&) {
+        // nothing was buffered
+    }
+}
+
+// Returns the key of the chromagram collected so far
+extern "C"
+int32_t kfwrapper__key_of_chromagram(void* workspace) {
+    auto w = (KeyFinder::Workspace*)workspace;
+
+    // no audio was analysed yet
+    if (w->chromagram == nullptr) {
+        return (int32_t)KeyFinder::SILENCE;
+    }
+
+    try {
+        return (int32_t)kfwrapper_shared_keyfinder.keyOfChromagram(*w);
+    } catch (const std::exception&) {
+        return (int32_t)KeyFinder::SILENCE;
+    }
+}
+
+// Writes the chroma vector of the chromagram collected so far into `chroma`: the
+// magnitude of every band summed over all hops and octaves, indexed by pitch class with
+// C being 0. The Rust side scores it against the same key templates as the native
+// detector, as libkeyfinder only exposes the best key. Returns false if there is no
+// chromagram yet.
+extern "C"
+bool kfwrapper__chroma_of_chromagram(void* workspace, double* chroma) {
+    auto w = (KeyFinder::Workspace*)workspace;
+
+    // no audio was analysed yet
+    if (w->chromagram == nullptr) {
+        return false;
+    }
+
+    for (int pitch_class = 0; pitch_class < 12; pitch_class++) {
+        chroma[pitch_class] = 0.0;
+    }
+
+    // the bands are semitones starting at A
+    const unsigned int hops = w->chromagram->getHops();
+    const unsigned int bands = w->chromagram->getBands();
+    for (unsigned int hop = 0; hop < hops; hop++) {
+        for (unsigned int band = 0; band < bands; band++) {
+            chroma[(band + 9) % 12] += w->chromagram->getMagnitude(hop, band);
+        }
+    }
+    return true;
+}
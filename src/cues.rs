@@ -0,0 +1,347 @@
+// Cue points
+// ----------
+//
+// Finds the sections transitions are planned around: the stripped down intro and outro
+// a track can be mixed in and out over, breakdowns in the middle of the track and the
+// drops that follow them. The RMS envelope of the loudness meter is averaged per bar of
+// the beat grid (or per `FALLBACK_UNIT_SECS` without one), each bar is compared to the
+// level of the main body of the track and runs of quiet bars become sections, so cues
+// always start and end on a bar line.
+
+use serde::{Deserialize, Serialize};
+
+use crate::loudness::STEP_SECS;
+use crate::tempo::{BeatAnchor, BeatGrid, BEATS_PER_BAR};
+
+// The level of the main body of the track is the level this share of the bars is below
+const BODY_PERCENTILE: f64 = 0.8;
+
+// Bars at least this far below the main body are low density
+const LOW_DENSITY_DB: f32 = 4.0;
+
+// Sections shorter than this many bars are merged into their neighbours, so a fill or a
+// single quiet bar doesn't make a breakdown
+const MIN_SECTION_BARS: f64 = 4.0;
+
+// The length of the units the envelope is averaged over when there is no beat grid
+const FALLBACK_UNIT_SECS: f64 = 2.0;
+
+// What a section of a track is used for in a mix
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CueKind {
+    // The low density start of the track, to mix it in over the previous one
+    Intro,
+    // A low density section between two full ones
+    Breakdown,
+    // The full section following a breakdown
+    Drop,
+    // The low density end of the track, to mix the next one in over
+    Outro,
+}
+
+impl CueKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CueKind::Intro => "intro",
+            CueKind::Breakdown => "breakdown",
+            CueKind::Drop => "drop",
+            CueKind::Outro => "outro",
+        }
+    }
+}
+
+// A section of a track
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct CuePoint {
+    pub kind: CueKind,
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+impl std::fmt::Display for CuePoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}-{}",
+            self.kind.name(),
+            format_secs(self.start_secs),
+            format_secs(self.end_secs)
+        )
+    }
+}
+
+// A run of bars that are all either low density or full
+struct Section {
+    low: bool,
+    start: usize,
+    end: usize,
+    secs: f64,
+}
+
+// Finds the cue points of a track from its RMS envelope (one level in dBFS for every
+// `STEP_SECS`) and its beat grid, if it has one
+pub fn find_cues(
+    rms_db: &[f32],
+    beat_grid: Option<&BeatGrid>,
+    duration_secs: f64,
+) -> Vec<CuePoint> {
+    let units = unit_bounds(rms_db.len(), beat_grid);
+    if units.len() < 2 {
+        return vec![];
+    }
+    let bar_secs = bar_secs(beat_grid.and_then(|grid| grid.anchors.first()));
+
+    // the level of every unit, compared to the main body
+    let levels: Vec<f32> = units
+        .windows(2)
+        .map(|bounds| mean_db(&rms_db[bounds[0]..bounds[1]]))
+        .collect();
+    let mut sorted = levels.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let body_db = sorted[((sorted.len() - 1) as f64 * BODY_PERCENTILE).round() as usize];
+
+    // group the units into runs of low density and full ones
+    let mut sections: Vec<Section> = vec![];
+    for (unit, level) in levels.iter().enumerate() {
+        let low = *level < body_db - LOW_DENSITY_DB;
+        let secs = (units[unit + 1] - units[unit]) as f64 * STEP_SECS;
+        match sections.last_mut() {
+            Some(section) if section.low == low => {
+                section.end = unit + 1;
+                section.secs += secs;
+            }
+            _ => sections.push(Section {
+                low,
+                start: unit,
+                end: unit + 1,
+                secs,
+            }),
+        }
+    }
+
+    // flip the shortest sections into their neighbours until every section is long enough
+    let min_secs = MIN_SECTION_BARS * bar_secs;
+    while sections.len() > 1 {
+        let (shortest, section) = sections
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.secs.total_cmp(&b.secs))
+            .unwrap();
+        if section.secs >= min_secs {
+            break;
+        }
+
+        // the neighbours have the other density, so the flipped section joins both
+        let first = shortest.saturating_sub(1);
+        let last = (shortest + 1).min(sections.len() - 1);
+        let merged = Section {
+            low: !sections[shortest].low,
+            start: sections[first].start,
+            end: sections[last].end,
+            secs: sections[first..=last]
+                .iter()
+                .map(|section| section.secs)
+                .sum(),
+        };
+        sections.splice(first..=last, [merged]);
+    }
+    if sections.len() < 2 {
+        return vec![];
+    }
+
+    let secs_of_unit = |unit: usize| match unit {
+        unit if unit + 1 >= units.len() => duration_secs,
+        unit => units[unit] as f64 * STEP_SECS,
+    };
+    let last = sections.len() - 1;
+    let mut cues = vec![];
+    for (index, section) in sections.iter().enumerate() {
+        let kind = match (section.low, index) {
+            (true, 0) => CueKind::Intro,
+            (true, index) if index == last => CueKind::Outro,
+            (true, _) => CueKind::Breakdown,
+            (false, index) if index > 1 && sections[index - 1].low => CueKind::Drop,
+            (false, _) => continue,
+        };
+        cues.push(CuePoint {
+            kind,
+            start_secs: secs_of_unit(section.start),
+            end_secs: secs_of_unit(section.end),
+        });
+    }
+    cues
+}
+
+// Returns the step indices the averaging units start at, followed by the end of the
+// envelope: the downbeats of the beat grid, or fixed length units without one
+fn unit_bounds(step_count: usize, beat_grid: Option<&BeatGrid>) -> Vec<usize> {
+    let end_secs = step_count as f64 * STEP_SECS;
+    let downbeats: Vec<f64> = match beat_grid {
+        Some(grid) => (0..grid.beat_count)
+            .filter(|beat| grid.is_downbeat(*beat))
+            .map(|beat| grid.beat_secs(beat))
+            .collect(),
+        None => vec![],
+    };
+
+    let mut starts = vec![];
+    match (downbeats.first(), downbeats.last(), beat_grid) {
+        (Some(first), Some(last), Some(grid)) => {
+            // the grid may start late and end early (on a silent or beatless passage), so
+            // its bars continue before and after it at the tempo of the nearest anchor
+            let first_bar = bar_secs(grid.anchors.first());
+            let mut secs = *first - first_bar;
+            while secs > 0.0 {
+                starts.push(secs);
+                secs -= first_bar;
+            }
+            starts.reverse();
+            starts.extend_from_slice(&downbeats);
+
+            let last_bar = bar_secs(grid.anchors.last());
+            let mut secs = *last + last_bar;
+            while secs < end_secs {
+                starts.push(secs);
+                secs += last_bar;
+            }
+        }
+        _ => {
+            let mut secs = FALLBACK_UNIT_SECS;
+            while secs < end_secs {
+                starts.push(secs);
+                secs += FALLBACK_UNIT_SECS;
+            }
+        }
+    }
+
+    let mut bounds = vec![0];
+    bounds.extend(
+        starts
+            .iter()
+            .map(|secs| (secs / STEP_SECS).round() as usize),
+    );
+    bounds.push(step_count);
+
+    // every unit needs at least one step
+    bounds.retain(|bound| *bound <= step_count);
+    bounds.dedup();
+    if bounds.len() == 1 {
+        return vec![];
+    }
+    bounds
+}
+
+// The length of a bar at the tempo of an anchor, or of a unit without a beat grid
+fn bar_secs(anchor: Option<&BeatAnchor>) -> f64 {
+    match anchor {
+        Some(anchor) => BEATS_PER_BAR as f64 * 60.0 / anchor.bpm,
+        None => FALLBACK_UNIT_SECS,
+    }
+}
+
+// Averages RMS levels in dBFS by their power
+fn mean_db(rms_db: &[f32]) -> f32 {
+    let power: f64 = rms_db
+        .iter()
+        .map(|db| 10f64.powf(*db as f64 / 10.0))
+        .sum::<f64>()
+        / rms_db.len() as f64;
+    (10.0 * power.log10()) as f32
+}
+
+// Formats seconds as minutes and seconds, like "3:07"
+fn format_secs(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An RMS envelope of runs of constant levels, given as (seconds, dBFS)
+    fn envelope(runs: &[(f64, f32)]) -> Vec<f32> {
+        runs.iter()
+            .flat_map(|(secs, db)| vec![*db; (secs / STEP_SECS).round() as usize])
+            .collect()
+    }
+
+    // A steady 120 BPM grid (a bar every 2 seconds) from the start of the track
+    fn grid(secs: f64) -> BeatGrid {
+        BeatGrid {
+            anchors: vec![BeatAnchor {
+                beat: 0,
+                secs: 0.0,
+                bpm: 120.0,
+            }],
+            beat_count: (secs * 2.0) as u32,
+            bar_phase: 0,
+        }
+    }
+
+    fn cue(kind: CueKind, start_secs: f64, end_secs: f64) -> CuePoint {
+        CuePoint {
+            kind,
+            start_secs,
+            end_secs,
+        }
+    }
+
+    #[test]
+    fn intro_from_a_quiet_start() {
+        let rms_db = envelope(&[(16.0, -30.0), (48.0, -10.0)]);
+        assert_eq!(
+            find_cues(&rms_db, Some(&grid(64.0)), 64.0),
+            vec![cue(CueKind::Intro, 0.0, 16.0)]
+        );
+    }
+
+    #[test]
+    fn breakdown_and_drop() {
+        let rms_db = envelope(&[(32.0, -10.0), (16.0, -25.0), (32.0, -10.0)]);
+        assert_eq!(
+            find_cues(&rms_db, Some(&grid(80.0)), 80.0),
+            vec![
+                cue(CueKind::Breakdown, 32.0, 48.0),
+                cue(CueKind::Drop, 48.0, 80.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn short_sections_are_merged() {
+        // two quiet bars are a fill, not a breakdown
+        let rms_db = envelope(&[(32.0, -10.0), (4.0, -25.0), (32.0, -10.0)]);
+        assert_eq!(find_cues(&rms_db, Some(&grid(68.0)), 68.0), vec![]);
+
+        // and a single full bar doesn't split a breakdown
+        let rms_db = envelope(&[
+            (32.0, -10.0),
+            (8.0, -25.0),
+            (2.0, -10.0),
+            (8.0, -25.0),
+            (32.0, -10.0),
+        ]);
+        assert_eq!(
+            find_cues(&rms_db, Some(&grid(82.0)), 82.0),
+            vec![
+                cue(CueKind::Breakdown, 32.0, 50.0),
+                cue(CueKind::Drop, 50.0, 82.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn fallback_units_without_a_beat_grid() {
+        let rms_db = envelope(&[(10.0, -30.0), (50.0, -10.0), (10.0, -30.0)]);
+        assert_eq!(
+            find_cues(&rms_db, None, 70.0),
+            vec![
+                cue(CueKind::Intro, 0.0, 10.0),
+                cue(CueKind::Outro, 60.0, 70.0)
+            ]
+        );
+        assert_eq!(unit_bounds(25, None), vec![0, 20, 25]);
+    }
+}
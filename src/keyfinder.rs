@@ -0,0 +1,989 @@
+// Native musical key detection
+// ----------------------------
+//
+// A pure-Rust take on the libkeyfinder approach: the incoming audio is cut into
+// windowed FFT frames, the spectrum of each frame is folded into a constant-Q
+// style pitch-class profile (a "chroma" vector) and the chromagram of the whole
+// track is correlated against major and minor key templates.
+//
+// Like libkeyfinder, analysis is streaming: each decoded block is turned into
+// chroma vectors straight away and summed up in a workspace, which only keeps the
+// samples of one unfinished analysis window. The key is read from the workspace once
+// the stream ends (or at any time before that, for a progressive estimate).
+//
+// Besides the key of the whole stream, each workspace keeps a key for every
+// `TIMELINE_BLOCK_SECS` block of audio. `ChannelAudio::key_timeline()` smooths those
+// into the sections of a track that modulates (or of a DJ mix that changes key).
+
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use serde::{Deserialize, Serialize};
+
+use crate::SongKey;
+
+#[cfg(has_libkeyfinder)]
+mod libkeyfinder;
+
+#[cfg(has_libkeyfinder)]
+pub use libkeyfinder::{LibAudioData, LibKeyFinder, LibWorkspace};
+
+// The key finder used by the indexer: libkeyfinder when the crate is built with the
+// `libkeyfinder` feature and the library was found, the native detector otherwise
+#[cfg(has_libkeyfinder)]
+pub type KeyFinder = LibKeyFinder;
+#[cfg(has_libkeyfinder)]
+pub type AudioData = LibAudioData;
+#[cfg(has_libkeyfinder)]
+pub type Workspace = LibWorkspace;
+
+#[cfg(not(has_libkeyfinder))]
+pub type KeyFinder = NativeKeyFinder;
+#[cfg(not(has_libkeyfinder))]
+pub type AudioData = NativeAudioData;
+#[cfg(not(has_libkeyfinder))]
+pub type Workspace = NativeWorkspace;
+
+// The frame rate used until `set_frame_rate()` is called
+const DEFAULT_FRAME_RATE: u32 = 44100;
+
+// The length of the analysis window in seconds (rounded to a power of two frames)
+const ANALYSIS_WINDOW_SECS: f32 = 0.37;
+
+// The number of hops per analysis window
+const HOPS_PER_WINDOW: usize = 4;
+
+// The lowest pitch we analyse (A1)
+const LOWEST_PITCH_HZ: f32 = 55.0;
+
+// The number of octaves covered by the pitch-class profile
+const OCTAVES: usize = 6;
+
+// The total number of semitone bands in the spectral kernel
+const BANDS: usize = OCTAVES * 12;
+
+// The pitch class of the lowest band (A), with C being 0
+const LOWEST_PITCH_CLASS: usize = 9;
+
+// Chroma energy below this is treated as silence
+const SILENCE_THRESHOLD: f32 = 1e-6;
+
+// Audio shorter than this (a couple of analysis windows) is too short to find a key in
+pub const MIN_AUDIO_SECS: f64 = 1.0;
+
+// The length of the blocks the key timeline is built from
+const TIMELINE_BLOCK_SECS: f64 = 10.0;
+
+// A single chroma vector, indexed by pitch class (C = 0)
+pub type Chroma = [f32; 12];
+
+// The key templates the chromagram is matched against
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ToneProfile {
+    // Krumhansl & Kessler probe-tone ratings
+    #[default]
+    Krumhansl,
+    // Temperley's corpus-derived (Kostka-Payne) profiles
+    Temperley,
+}
+
+impl ToneProfile {
+    // Returns the (major, minor) templates with the tonic at index 0
+    fn templates(&self) -> (&'static Chroma, &'static Chroma) {
+        match self {
+            Self::Krumhansl => (
+                &[
+                    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+                ],
+                &[
+                    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+                ],
+            ),
+            Self::Temperley => (
+                &[5.0, 2.0, 3.5, 2.0, 4.5, 4.0, 2.0, 4.5, 2.0, 3.5, 1.5, 4.0],
+                &[5.0, 2.0, 3.5, 4.5, 2.0, 4.0, 2.0, 4.5, 3.5, 2.0, 1.5, 4.0],
+            ),
+        }
+    }
+}
+
+// The audio to analyse, owned by the caller and filled while decoding
+pub struct NativeAudioData {
+    frame_rate: u32,
+    channels: u32,
+
+    // interleaved samples
+    samples: Vec<f32>,
+}
+
+impl Default for NativeAudioData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NativeAudioData {
+    pub fn new() -> Self {
+        NativeAudioData {
+            frame_rate: DEFAULT_FRAME_RATE,
+            channels: 1,
+            samples: vec![],
+        }
+    }
+
+    pub fn set_frame_rate(&mut self, frame_rate: u32) {
+        self.frame_rate = frame_rate;
+    }
+
+    pub fn frame_rate(&self) -> u32 {
+        self.frame_rate
+    }
+
+    // Sets the number of interleaved channels in the samples (0 is treated as mono)
+    pub fn set_channels(&mut self, channels: u32) {
+        self.channels = channels.max(1);
+    }
+
+    pub fn channels(&self) -> u32 {
+        self.channels
+    }
+
+    // Appends a block of interleaved samples
+    pub fn add_samples(&mut self, samples: &[f32]) {
+        self.samples.extend_from_slice(samples);
+    }
+
+    // The number of complete frames (one sample for each channel) added so far
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    // Returns the mono downmix of the frames
+    fn mono_samples(&self) -> impl Iterator<Item = f32> + '_ {
+        self.samples
+            .chunks_exact(self.channels as usize)
+            .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+    }
+}
+
+// Collects the chromagram of a stream of audio blocks. The blocks fed into a workspace
+// must all have the same frame rate.
+pub struct NativeWorkspace {
+    // mono samples not yet covered by a complete analysis window
+    pending: Vec<f32>,
+
+    // the sum of the chroma vectors of every analysed window
+    chroma: Chroma,
+
+    // the stream position of the first pending sample, and the total number of frames
+    pending_start: usize,
+    frame_count: usize,
+
+    // the length of a timeline block in frames (0 until the frame rate is known) and the
+    // summed chroma vectors of the windows starting in each block
+    block_frames: usize,
+    blocks: Vec<Chroma>,
+}
+
+impl Default for NativeWorkspace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NativeWorkspace {
+    pub fn new() -> Self {
+        NativeWorkspace {
+            pending: vec![],
+            chroma: [0.0; 12],
+            pending_start: 0,
+            frame_count: 0,
+            block_frames: 0,
+            blocks: vec![],
+        }
+    }
+
+    // Adds the chroma vector of the window starting `start` frames into the pending samples
+    fn add_chroma(&mut self, start: usize, chroma: &Chroma) {
+        let block = (self.pending_start + start) / self.block_frames;
+        if self.blocks.len() <= block {
+            self.blocks.resize(block + 1, [0.0; 12]);
+        }
+
+        for sum in [&mut self.chroma, &mut self.blocks[block]] {
+            for (sum, value) in sum.iter_mut().zip(chroma.iter()) {
+                *sum += value;
+            }
+        }
+    }
+}
+
+// The resources needed to analyse audio at a specific frame rate
+struct Analyser {
+    frame_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    kernel: Vec<Vec<(usize, f32)>>,
+}
+
+impl Analyser {
+    fn new(frame_rate: u32) -> Self {
+        let window_size = analysis_window_size(frame_rate);
+
+        Analyser {
+            frame_rate,
+            fft: FftPlanner::new().plan_fft_forward(window_size),
+            window: blackman_window(window_size),
+            kernel: spectral_kernel(frame_rate, window_size),
+        }
+    }
+
+    // Computes the chroma vector of a window of mono samples
+    fn chroma_of_window(&self, samples: &[f32]) -> Chroma {
+        let mut buffer: Vec<Complex<f32>> = self
+            .window
+            .iter()
+            .zip(samples.iter())
+            .map(|(weight, sample)| Complex::new(sample * weight, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+
+        let mut chroma: Chroma = [0.0; 12];
+        for (band, weights) in self.kernel.iter().enumerate() {
+            let energy: f32 = weights
+                .iter()
+                .map(|&(bin, weight)| buffer[bin].norm() * weight)
+                .sum();
+            chroma[(LOWEST_PITCH_CLASS + band) % 12] += energy;
+        }
+        chroma
+    }
+}
+
+// Retains the analysis resources between tracks, so a single instance should be
+// reused for a whole indexing run
+pub struct NativeKeyFinder {
+    tone_profile: ToneProfile,
+
+    // resources for the frame rate of the last analysed audio
+    analyser: Option<Analyser>,
+}
+
+impl Default for NativeKeyFinder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NativeKeyFinder {
+    pub fn new() -> Self {
+        NativeKeyFinder {
+            tone_profile: ToneProfile::default(),
+            analyser: None,
+        }
+    }
+
+    pub fn set_tone_profile(&mut self, tone_profile: ToneProfile) {
+        self.tone_profile = tone_profile;
+    }
+
+    // Returns the key of the audio
+    pub fn key_of_audio(&mut self, audio_data: &NativeAudioData) -> SongKey {
+        let mut workspace = NativeWorkspace::new();
+        self.progressive_chromagram(audio_data, &mut workspace);
+        self.final_chromagram(&mut workspace);
+        self.key_of_chromagram(&workspace)
+    }
+
+    // Adds the chroma vectors of every analysis window completed by a block of audio
+    // to the workspace
+    pub fn progressive_chromagram(
+        &mut self,
+        audio_data: &NativeAudioData,
+        workspace: &mut NativeWorkspace,
+    ) {
+        let analyser = self.analyser(audio_data.frame_rate());
+        let window_size = analyser.window.len();
+        let hop_size = window_size / HOPS_PER_WINDOW;
+
+        if workspace.block_frames == 0 {
+            workspace.block_frames =
+                ((TIMELINE_BLOCK_SECS * audio_data.frame_rate() as f64) as usize).max(1);
+        }
+        workspace.pending.extend(audio_data.mono_samples());
+        workspace.frame_count += audio_data.frame_count();
+
+        let mut start = 0;
+        while start + window_size <= workspace.pending.len() {
+            let chroma = analyser.chroma_of_window(&workspace.pending[start..start + window_size]);
+            workspace.add_chroma(start, &chroma);
+            start += hop_size;
+        }
+        workspace.pending.drain(..start);
+        workspace.pending_start += start;
+    }
+
+    // Analyses the samples left in the workspace (zero padded to a full window), call it
+    // once the stream ends
+    pub fn final_chromagram(&mut self, workspace: &mut NativeWorkspace) {
+        if let Some(analyser) = &self.analyser {
+            let window_size = analyser.window.len();
+            let pending = workspace.pending.len();
+            if pending >= window_size / HOPS_PER_WINDOW {
+                workspace.pending.resize(window_size, 0.0);
+                let chroma = analyser.chroma_of_window(&workspace.pending);
+                workspace.add_chroma(0, &chroma);
+            }
+            workspace.pending_start += pending;
+        }
+        workspace.pending.clear();
+    }
+
+    // Returns the key of the chromagram collected so far
+    pub fn key_of_chromagram(&self, workspace: &NativeWorkspace) -> SongKey {
+        self.key_of_chroma(&workspace.chroma)
+    }
+
+    // Returns how well the chromagram collected so far matches each key, best match first
+    pub fn key_scores_of_chromagram(&self, workspace: &NativeWorkspace) -> Vec<KeyScore> {
+        key_scores_of_chroma(self.tone_profile, &workspace.chroma)
+    }
+
+    // Returns the length in frames and the key of every timeline block analysed so far
+    pub fn key_timeline(&self, workspace: &NativeWorkspace) -> Vec<(usize, SongKey)> {
+        workspace
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(block, chroma)| {
+                let start = block * workspace.block_frames;
+                let frames = workspace.block_frames.min(workspace.frame_count - start);
+                (frames, self.key_of_chroma(chroma))
+            })
+            .collect()
+    }
+
+    // Returns the analysis resources for a frame rate, reusing the last ones if possible
+    fn analyser(&mut self, frame_rate: u32) -> &Analyser {
+        if self
+            .analyser
+            .as_ref()
+            .is_some_and(|analyser| analyser.frame_rate != frame_rate)
+        {
+            self.analyser = None;
+        }
+        self.analyser
+            .get_or_insert_with(|| Analyser::new(frame_rate))
+    }
+
+    // Correlates a chroma vector against all 24 key templates and returns the best match
+    fn key_of_chroma(&self, chroma: &Chroma) -> SongKey {
+        key_scores_of_chroma(self.tone_profile, chroma)
+            .first()
+            .map(|score| score.key)
+            .unwrap_or(SongKey::Unknown)
+    }
+}
+
+// How the channels of multichannel audio are turned into key finder input
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChannelMode {
+    // analyse the average of all channels (L+R for stereo)
+    Mid,
+    // analyse every channel on its own and use the key most channels agree on
+    Vote,
+    // only analyse a single channel, counting from 0 (the last one if out of range)
+    Channel(usize),
+}
+
+impl std::str::FromStr for ChannelMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mid" => Ok(ChannelMode::Mid),
+            "vote" => Ok(ChannelMode::Vote),
+            "left" => Ok(ChannelMode::Channel(0)),
+            "right" => Ok(ChannelMode::Channel(1)),
+            other => match other.parse::<usize>() {
+                Ok(channel) => Ok(ChannelMode::Channel(channel)),
+                Err(_) => Err(format!(
+                    "invalid channel mode \"{}\": expected mid, vote, left, right or a channel number",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+// Called with the number of seconds analysed so far and the key estimated from them
+pub type EstimateCallback = Box<dyn FnMut(f64, SongKey)>;
+
+// Streams decoded audio into the key finder according to a channel mode
+pub struct ChannelAudio {
+    mode: ChannelMode,
+    frame_rate: u32,
+
+    // a single workspace for the mono mix, or one for each channel when voting
+    workspaces: Vec<Workspace>,
+
+    // the signal energy of each workspace, used to break ties when voting
+    energy: Vec<f64>,
+
+    // the number of frames added so far
+    frame_count: usize,
+
+    // the progressive estimate callback with its interval in frames, and the frame
+    // count at which it is called next
+    estimate: Option<(usize, EstimateCallback)>,
+    next_estimate: usize,
+}
+
+impl ChannelAudio {
+    pub fn new(mode: ChannelMode, frame_rate: u32) -> Self {
+        ChannelAudio {
+            mode,
+            frame_rate,
+            workspaces: vec![],
+            energy: vec![],
+            frame_count: 0,
+            estimate: None,
+            next_estimate: 0,
+        }
+    }
+
+    // Calls `callback` with the key estimated so far after every `interval_secs` of audio
+    pub fn on_estimate(&mut self, interval_secs: f64, callback: EstimateCallback) {
+        let interval = ((interval_secs * self.frame_rate as f64) as usize).max(1);
+        self.estimate = Some((interval, callback));
+        self.next_estimate = self.frame_count + interval;
+    }
+
+    // Feeds a block of planar samples into the workspaces the channel mode calls for, and
+    // calls the estimate callback once its interval has passed
+    pub fn add_planar_samples(
+        &mut self,
+        key_finder: &mut KeyFinder,
+        samples: &[f32],
+        channel_count: usize,
+    ) {
+        if channel_count == 0 {
+            return;
+        }
+        let frames = samples.len() / channel_count;
+        let plane = |channel: usize| &samples[channel * frames..(channel + 1) * frames];
+
+        match self.mode {
+            ChannelMode::Mid => {
+                let mid: Vec<f32> = crate::mono_downmix(samples, channel_count).collect();
+                self.add_to_workspace(key_finder, 0, &mid);
+            }
+            ChannelMode::Vote => {
+                for channel in 0..channel_count {
+                    self.add_to_workspace(key_finder, channel, plane(channel));
+                }
+            }
+            ChannelMode::Channel(selected) => {
+                self.add_to_workspace(key_finder, 0, plane(selected.min(channel_count - 1)));
+            }
+        }
+
+        self.frame_count += frames;
+
+        // finding the key of everything so far is expensive, so only do it for a callback
+        if self.estimate.is_some() && self.frame_count >= self.next_estimate {
+            let key = self.current_key(key_finder);
+            let secs = self.duration_secs();
+            if let Some((interval, callback)) = &mut self.estimate {
+                callback(secs, key);
+                self.next_estimate = self.frame_count + *interval;
+            }
+        }
+    }
+
+    fn add_to_workspace(&mut self, key_finder: &mut KeyFinder, index: usize, samples: &[f32]) {
+        while self.workspaces.len() <= index {
+            self.workspaces.push(Workspace::new());
+            self.energy.push(0.0);
+        }
+
+        let mut audio_data = AudioData::new();
+        audio_data.set_frame_rate(self.frame_rate);
+        audio_data.set_channels(1);
+        audio_data.add_samples(samples);
+
+        self.energy[index] += samples.iter().map(|s| (s * s) as f64).sum::<f64>();
+        key_finder.progressive_chromagram(&audio_data, &mut self.workspaces[index]);
+    }
+
+    // The length of the audio added so far
+    pub fn duration_secs(&self) -> f64 {
+        self.frame_count as f64 / self.frame_rate as f64
+    }
+
+    // Returns the key of the audio analysed so far, without the samples still waiting
+    // for a complete analysis window
+    pub fn current_key(&self, key_finder: &KeyFinder) -> SongKey {
+        if self.workspaces.len() == 1 {
+            return key_finder.key_of_chromagram(&self.workspaces[0]);
+        }
+
+        vote(
+            self.workspaces
+                .iter()
+                .map(|workspace| key_finder.key_of_chromagram(workspace))
+                .zip(self.energy.iter().copied()),
+        )
+    }
+
+    // Returns how well the audio analysed so far matches each key, best match first. When
+    // voting, these are the scores of the loudest channel that voted for the winning key.
+    pub fn key_scores(&self, key_finder: &KeyFinder) -> Vec<KeyScore> {
+        let key = self.current_key(key_finder);
+        if key == SongKey::Unknown {
+            return vec![];
+        }
+
+        self.workspaces
+            .iter()
+            .zip(self.energy.iter())
+            .filter(|(workspace, _)| key_finder.key_of_chromagram(workspace) == key)
+            .max_by(|(_, a_energy), (_, b_energy)| a_energy.total_cmp(b_energy))
+            .map(|(workspace, _)| key_finder.key_scores_of_chromagram(workspace))
+            .unwrap_or_default()
+    }
+
+    // Analyses the rest of the audio and returns the key of the whole stream
+    pub fn finish(&mut self, key_finder: &mut KeyFinder) -> SongKey {
+        for workspace in &mut self.workspaces {
+            key_finder.final_chromagram(workspace);
+        }
+        self.current_key(key_finder)
+    }
+
+    // Returns the sections of the stream that are in a different key, call it after
+    // `finish()`. Blocks are voted on like whole streams, then a block that differs from
+    // both of its (agreeing) neighbours is taken as a misdetection and adjacent blocks
+    // with the same key are merged.
+    pub fn key_timeline(&self, key_finder: &KeyFinder) -> Vec<KeySegment> {
+        let timelines: Vec<Vec<(usize, SongKey)>> = self
+            .workspaces
+            .iter()
+            .map(|workspace| key_finder.key_timeline(workspace))
+            .collect();
+        let block_count = timelines.iter().map(Vec::len).max().unwrap_or(0);
+
+        let blocks: Vec<(usize, SongKey)> = (0..block_count)
+            .map(|block| {
+                let channel_blocks = timelines
+                    .iter()
+                    .zip(self.energy.iter())
+                    .filter_map(|(timeline, energy)| Some((timeline.get(block)?, *energy)));
+                let frames = channel_blocks
+                    .clone()
+                    .map(|((frames, _), _)| *frames)
+                    .max()
+                    .unwrap_or(0);
+                (
+                    frames,
+                    vote(channel_blocks.map(|((_, key), energy)| (*key, energy))),
+                )
+            })
+            .collect();
+
+        let smoothed: Vec<SongKey> = (0..blocks.len())
+            .map(
+                |block| match (block.checked_sub(1), blocks.get(block + 1)) {
+                    (Some(previous), Some((_, next))) if blocks[previous].1 == *next => *next,
+                    _ => blocks[block].1,
+                },
+            )
+            .collect();
+
+        let secs = |frames: usize| frames as f64 / self.frame_rate as f64;
+        let mut segments: Vec<KeySegment> = vec![];
+        // the stream position, and the frames of the current segment along with how many
+        // of them were detected in its key
+        let mut position = 0;
+        let mut frames = 0;
+        let mut agreeing_frames = 0;
+        for (&(block_frames, key), &smoothed_key) in blocks.iter().zip(smoothed.iter()) {
+            if segments.last().map(|segment| segment.key) != Some(smoothed_key) {
+                segments.push(KeySegment {
+                    start_secs: secs(position),
+                    end_secs: secs(position),
+                    key: smoothed_key,
+                    confidence: 0.0,
+                });
+                frames = 0;
+                agreeing_frames = 0;
+            }
+
+            position += block_frames;
+            frames += block_frames;
+            if key == smoothed_key {
+                agreeing_frames += block_frames;
+            }
+            if let Some(segment) = segments.last_mut() {
+                segment.end_secs = secs(position);
+                segment.confidence = agreeing_frames as f32 / frames.max(1) as f32;
+            }
+        }
+
+        // the samples after the last analysis window still belong to the last segment
+        if let Some(segment) = segments.last_mut() {
+            segment.end_secs = self.duration_secs();
+        }
+        segments
+    }
+}
+
+// A section of a stream in a single key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KeySegment {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub key: SongKey,
+    // The share of the section that was detected in this key before smoothing (0 to 1)
+    pub confidence: f32,
+}
+
+// Returns the key most channels agree on, breaking ties by the signal energy of the
+// channels and ignoring silent ones
+fn vote(keys: impl Iterator<Item = (SongKey, f64)>) -> SongKey {
+    let mut tally: Vec<(SongKey, usize, f64)> = vec![];
+    for (key, energy) in keys {
+        if key == SongKey::Unknown {
+            continue;
+        }
+        match tally.iter_mut().find(|(k, _, _)| *k == key) {
+            Some((_, votes, key_energy)) => {
+                *votes += 1;
+                *key_energy += energy;
+            }
+            None => tally.push((key, 1, energy)),
+        }
+    }
+
+    tally
+        .into_iter()
+        .max_by(|(_, a_votes, a_energy), (_, b_votes, b_energy)| {
+            a_votes.cmp(b_votes).then(a_energy.total_cmp(b_energy))
+        })
+        .map(|(key, _, _)| key)
+        .unwrap_or(SongKey::Unknown)
+}
+
+// How well the audio matches a key: the correlation of the chromagram with the key
+// template, for both the native detector and libkeyfinder
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KeyScore {
+    pub key: SongKey,
+    pub score: f32,
+}
+
+// Sorts key scores best match first
+fn sort_key_scores(scores: &mut [KeyScore]) {
+    scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+// Returns the correlation of a chroma vector with all 24 key templates, best match first,
+// or nothing for silence
+fn key_scores_of_chroma(tone_profile: ToneProfile, chroma: &Chroma) -> Vec<KeyScore> {
+    if chroma.iter().sum::<f32>() < SILENCE_THRESHOLD {
+        return vec![];
+    }
+
+    let (major, minor) = tone_profile.templates();
+    let mut scores = vec![];
+    for tonic in 0..12 {
+        for (template, minor_mode) in [(major, false), (minor, true)] {
+            scores.push(KeyScore {
+                key: SongKey::from_pitch_class(tonic, minor_mode),
+                score: correlation(chroma, template, tonic),
+            });
+        }
+    }
+
+    sort_key_scores(&mut scores);
+    scores
+}
+
+// Returns the power-of-two window size closest to `ANALYSIS_WINDOW_SECS`
+fn analysis_window_size(frame_rate: u32) -> usize {
+    let ideal = (frame_rate as f32 * ANALYSIS_WINDOW_SECS).max(64.0);
+    1 << ideal.log2().round() as u32
+}
+
+fn blackman_window(size: usize) -> Vec<f32> {
+    use std::f32::consts::PI;
+
+    let n = (size - 1) as f32;
+    (0..size)
+        .map(|i| {
+            let x = i as f32 / n;
+            0.42 - 0.5 * (2.0 * PI * x).cos() + 0.08 * (4.0 * PI * x).cos()
+        })
+        .collect()
+}
+
+// Builds the constant-Q style kernel: for each semitone band the FFT bins within a
+// semitone of the band centre, raised-cosine weighted and normalised so that wide
+// high-frequency bands do not outweigh narrow low-frequency ones.
+fn spectral_kernel(frame_rate: u32, window_size: usize) -> Vec<Vec<(usize, f32)>> {
+    use std::f32::consts::PI;
+
+    let bin_hz = frame_rate as f32 / window_size as f32;
+    let nyquist_bin = window_size / 2;
+
+    (0..BANDS)
+        .map(|band| {
+            let centre_hz = LOWEST_PITCH_HZ * 2f32.powf(band as f32 / 12.0);
+            let low_bin = (centre_hz * 2f32.powf(-1.0 / 12.0) / bin_hz).floor() as usize;
+            let high_bin =
+                ((centre_hz * 2f32.powf(1.0 / 12.0) / bin_hz).ceil() as usize).min(nyquist_bin);
+
+            let mut weights: Vec<(usize, f32)> = (low_bin.max(1)..=high_bin)
+                .filter_map(|bin| {
+                    let semitones = 12.0 * (bin as f32 * bin_hz / centre_hz).log2();
+                    if semitones.abs() >= 1.0 {
+                        return None;
+                    }
+                    Some((bin, 0.5 * (1.0 + (PI * semitones).cos())))
+                })
+                .collect();
+
+            // always look at the nearest bin, even if the band is narrower than a bin
+            if weights.is_empty() {
+                let nearest = ((centre_hz / bin_hz).round() as usize).clamp(1, nyquist_bin);
+                weights.push((nearest, 1.0));
+            }
+
+            let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+            weights
+                .into_iter()
+                .map(|(bin, weight)| (bin, weight / total))
+                .collect()
+        })
+        .collect()
+}
+
+// Pearson correlation between a chroma vector and a template rotated to `tonic`
+fn correlation(chroma: &Chroma, template: &Chroma, tonic: usize) -> f32 {
+    let chroma_mean = chroma.iter().sum::<f32>() / 12.0;
+    let template_mean = template.iter().sum::<f32>() / 12.0;
+
+    let mut covariance = 0.0;
+    let mut chroma_variance = 0.0;
+    let mut template_variance = 0.0;
+    for pitch_class in 0..12 {
+        let c = chroma[pitch_class] - chroma_mean;
+        let t = template[(pitch_class + 12 - tonic) % 12] - template_mean;
+        covariance += c * t;
+        chroma_variance += c * c;
+        template_variance += t * t;
+    }
+
+    if chroma_variance == 0.0 || template_variance == 0.0 {
+        return 0.0;
+    }
+    covariance / (chroma_variance * template_variance).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A_MINOR: [f32; 3] = [220.0, 261.63, 329.63];
+    const C_MAJOR: [f32; 3] = [261.63, 329.63, 392.0];
+    const G_SHARP_MINOR: [f32; 3] = [207.65, 246.94, 311.13];
+
+    // Sine tones at the given frequencies
+    fn tones(frequencies: &[f32], frame_rate: u32, secs: usize) -> Vec<f32> {
+        (0..frame_rate as usize * secs)
+            .map(|i| {
+                let t = i as f32 / frame_rate as f32;
+                frequencies
+                    .iter()
+                    .map(|frequency| (2.0 * std::f32::consts::PI * frequency * t).sin())
+                    .sum::<f32>()
+                    / frequencies.len().max(1) as f32
+            })
+            .collect()
+    }
+
+    // A few seconds of a chord of sine tones at the given frequencies
+    fn chord(frequencies: &[f32], frame_rate: u32) -> NativeAudioData {
+        let mut audio_data = NativeAudioData::new();
+        audio_data.set_frame_rate(frame_rate);
+        audio_data.add_samples(&tones(frequencies, frame_rate, 4));
+        audio_data
+    }
+
+    // Streams channels of equal length through a channel mode as one planar block and
+    // returns the key of the stream with its scores
+    fn key_of_channels(mode: ChannelMode, channels: &[Vec<f32>]) -> (SongKey, Vec<KeyScore>) {
+        let mut key_finder = KeyFinder::new();
+        let mut channel_audio = ChannelAudio::new(mode, 22050);
+        channel_audio.add_planar_samples(&mut key_finder, &channels.concat(), channels.len());
+        let key = channel_audio.finish(&mut key_finder);
+        (key, channel_audio.key_scores(&key_finder))
+    }
+
+    #[test]
+    fn a_minor_triad() {
+        let audio_data = chord(&A_MINOR, 22050);
+        for tone_profile in [ToneProfile::Krumhansl, ToneProfile::Temperley] {
+            let mut key_finder = NativeKeyFinder::new();
+            key_finder.set_tone_profile(tone_profile);
+            assert_eq!(key_finder.key_of_audio(&audio_data), SongKey::AMin);
+        }
+    }
+
+    #[test]
+    fn c_major_triad() {
+        let audio_data = chord(&C_MAJOR, 44100);
+        for tone_profile in [ToneProfile::Krumhansl, ToneProfile::Temperley] {
+            let mut key_finder = NativeKeyFinder::new();
+            key_finder.set_tone_profile(tone_profile);
+            assert_eq!(key_finder.key_of_audio(&audio_data), SongKey::CMaj);
+        }
+    }
+
+    #[test]
+    fn silence_has_no_key() {
+        let audio_data = chord(&[], 22050);
+        assert_eq!(
+            NativeKeyFinder::new().key_of_audio(&audio_data),
+            SongKey::Unknown
+        );
+    }
+
+    // Streams sections of mono audio through the key finder in decoder sized blocks and
+    // returns the key timeline
+    fn key_timeline_of(sections: &[Vec<f32>], frame_rate: u32) -> Vec<KeySegment> {
+        let mut key_finder = KeyFinder::new();
+        let mut channel_audio = ChannelAudio::new(ChannelMode::Mid, frame_rate);
+        for block in sections.concat().chunks(4096) {
+            channel_audio.add_planar_samples(&mut key_finder, block, 1);
+        }
+        channel_audio.finish(&mut key_finder);
+        channel_audio.key_timeline(&key_finder)
+    }
+
+    #[test]
+    fn key_change() {
+        let segments = key_timeline_of(
+            &[tones(&A_MINOR, 11025, 30), tones(&G_SHARP_MINOR, 11025, 30)],
+            11025,
+        );
+        assert_eq!(
+            segments,
+            vec![
+                KeySegment {
+                    start_secs: 0.0,
+                    end_secs: 30.0,
+                    key: SongKey::AMin,
+                    confidence: 1.0,
+                },
+                KeySegment {
+                    start_secs: 30.0,
+                    end_secs: 60.0,
+                    key: SongKey::AfMin,
+                    confidence: 1.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn outlier_blocks_are_smoothed_away() {
+        let segments = key_timeline_of(
+            &[
+                tones(&A_MINOR, 11025, 20),
+                tones(&G_SHARP_MINOR, 11025, 10),
+                tones(&A_MINOR, 11025, 20),
+            ],
+            11025,
+        );
+        assert_eq!(
+            segments,
+            vec![KeySegment {
+                start_secs: 0.0,
+                end_secs: 50.0,
+                key: SongKey::AMin,
+                // the outlier block still counts against the segment
+                confidence: 0.8,
+            }]
+        );
+    }
+
+    #[test]
+    fn channel_selection() {
+        let channels = [tones(&A_MINOR, 22050, 4), tones(&C_MAJOR, 22050, 4)];
+        let key = |mode| key_of_channels(mode, &channels).0;
+        assert_eq!(key(ChannelMode::Channel(0)), SongKey::AMin);
+        assert_eq!(key(ChannelMode::Channel(1)), SongKey::CMaj);
+        // out of range channels select the last one
+        assert_eq!(key(ChannelMode::Channel(5)), SongKey::CMaj);
+    }
+
+    #[test]
+    fn mid_averages_the_channels() {
+        // a channel and its inverse cancel out in the mid signal, but not on their own
+        let left = tones(&A_MINOR, 22050, 4);
+        let right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+        let channels = [left, right];
+        assert_eq!(
+            key_of_channels(ChannelMode::Mid, &channels).0,
+            SongKey::Unknown
+        );
+        assert_eq!(
+            key_of_channels(ChannelMode::Vote, &channels).0,
+            SongKey::AMin
+        );
+
+        let channels = [tones(&A_MINOR, 22050, 4), tones(&A_MINOR, 22050, 4)];
+        assert_eq!(
+            key_of_channels(ChannelMode::Mid, &channels).0,
+            SongKey::AMin
+        );
+    }
+
+    #[test]
+    fn vote_by_channels() {
+        // the majority of the channels wins
+        let channels = [
+            tones(&C_MAJOR, 22050, 4),
+            tones(&A_MINOR, 22050, 4),
+            tones(&A_MINOR, 22050, 4),
+        ];
+        let (key, scores) = key_of_channels(ChannelMode::Vote, &channels);
+        assert_eq!(key, SongKey::AMin);
+        assert_eq!(scores[0].key, SongKey::AMin);
+
+        // a tie goes to the louder channel, whose scores are reported
+        let quiet: Vec<f32> = tones(&A_MINOR, 22050, 4).iter().map(|s| s * 0.2).collect();
+        let channels = [quiet, tones(&C_MAJOR, 22050, 4)];
+        let (key, scores) = key_of_channels(ChannelMode::Vote, &channels);
+        assert_eq!(key, SongKey::CMaj);
+        assert_eq!(scores[0].key, SongKey::CMaj);
+    }
+
+    #[test]
+    fn vote_ignores_unknown_keys() {
+        let votes = [
+            (SongKey::Unknown, 9.0),
+            (SongKey::Unknown, 9.0),
+            (SongKey::GMaj, 1.0),
+        ];
+        assert_eq!(vote(votes.into_iter()), SongKey::GMaj);
+        assert_eq!(
+            vote([(SongKey::Unknown, 1.0)].into_iter()),
+            SongKey::Unknown
+        );
+        assert_eq!(vote(std::iter::empty()), SongKey::Unknown);
+    }
+}
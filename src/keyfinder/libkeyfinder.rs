@@ -0,0 +1,263 @@
+// Key detection through Mixxx's libkeyfinder
+// ------------------------------------------
+//
+// Calls into the C wrapper in `native/kfwrapper.cpp`, which `build.rs` only compiles
+// when the `libkeyfinder` feature is enabled and the library is installed.
+
+use super::{KeyScore, ToneProfile};
+use crate::SongKey;
+
+// use a type alias so we can change this later for opaque struct
+type KeyFinderAudioDataPtr = *mut ::libc::c_void;
+type KeyFinderWorkspacePtr = *mut ::libc::c_void;
+
+extern "C" {
+
+    // intializer for the audio data
+    fn kfwrapper__init_audio_data(frame_rate: u32) -> KeyFinderAudioDataPtr;
+
+    // changes the frame rate of the audio data
+    fn kfwrapper__set_frame_rate(audio_data: KeyFinderAudioDataPtr, frame_rate: u32);
+
+    // changes the number of interleaved channels of the audio data
+    fn kfwrapper__set_channels(audio_data: KeyFinderAudioDataPtr, channels: u32);
+
+    // destructor for the audio data
+    fn kfwrapper__destroy_audio_data(audio_data: KeyFinderAudioDataPtr);
+
+    // add a number of samples to the audio data
+    fn kfwrapper__add_to_samples(
+        audio_data: KeyFinderAudioDataPtr,
+        data: *const f32,
+        data_size: u64,
+    );
+
+    // returns the current key of the audio data
+    fn kfwrapper__key_of_audio(audio_data: KeyFinderAudioDataPtr) -> i32;
+
+    // intializer for the workspace
+    fn kfwrapper__init_workspace() -> KeyFinderWorkspacePtr;
+
+    // destructor for the workspace
+    fn kfwrapper__destroy_workspace(workspace: KeyFinderWorkspacePtr);
+
+    // adds the chromagram of the audio data to the workspace
+    fn kfwrapper__progressive_chromagram(
+        audio_data: KeyFinderAudioDataPtr,
+        workspace: KeyFinderWorkspacePtr,
+    );
+
+    // flushes the buffered audio of the workspace into its chromagram
+    fn kfwrapper__final_chromagram(workspace: KeyFinderWorkspacePtr);
+
+    // returns the key of the chromagram in the workspace
+    fn kfwrapper__key_of_chromagram(workspace: KeyFinderWorkspacePtr) -> i32;
+
+    // writes the chroma vector (C = 0) of the chromagram in the workspace, returns false
+    // if there is no chromagram yet
+    fn kfwrapper__chroma_of_chromagram(workspace: KeyFinderWorkspacePtr, chroma: *mut f64) -> bool;
+
+}
+
+// Owns a libkeyfinder `AudioData` instance and frees it when dropped
+pub struct LibAudioData {
+    frame_rate: u32,
+    channels: u32,
+    sample_count: usize,
+    raw: KeyFinderAudioDataPtr,
+}
+
+// The audio data is only ever accessed through `&mut self` or by a single key finder
+// call at a time, so it is safe to move it to another thread
+unsafe impl Send for LibAudioData {}
+
+impl Default for LibAudioData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LibAudioData {
+    pub fn new() -> Self {
+        LibAudioData {
+            frame_rate: super::DEFAULT_FRAME_RATE,
+            channels: 1,
+            sample_count: 0,
+            raw: unsafe { kfwrapper__init_audio_data(super::DEFAULT_FRAME_RATE) },
+        }
+    }
+
+    pub fn set_frame_rate(&mut self, frame_rate: u32) {
+        self.frame_rate = frame_rate;
+        unsafe { kfwrapper__set_frame_rate(self.raw, frame_rate) }
+    }
+
+    pub fn frame_rate(&self) -> u32 {
+        self.frame_rate
+    }
+
+    // Sets the number of interleaved channels in the samples (0 is treated as mono)
+    pub fn set_channels(&mut self, channels: u32) {
+        self.channels = channels.max(1);
+        unsafe { kfwrapper__set_channels(self.raw, self.channels) }
+    }
+
+    pub fn channels(&self) -> u32 {
+        self.channels
+    }
+
+    // Appends a block of interleaved samples
+    pub fn add_samples(&mut self, samples: &[f32]) {
+        self.sample_count += samples.len();
+        unsafe { kfwrapper__add_to_samples(self.raw, samples.as_ptr(), samples.len() as u64) }
+    }
+
+    // The number of complete frames (one sample for each channel) added so far
+    pub fn frame_count(&self) -> usize {
+        self.sample_count / self.channels as usize
+    }
+}
+
+impl Drop for LibAudioData {
+    fn drop(&mut self) {
+        unsafe { kfwrapper__destroy_audio_data(self.raw) }
+    }
+}
+
+// Owns a libkeyfinder `Workspace`, which collects the chromagram of a stream. The
+// chromagram of the current timeline block is collected in a second workspace, as
+// libkeyfinder can only find the key of a whole chromagram.
+pub struct LibWorkspace {
+    raw: KeyFinderWorkspacePtr,
+    block_raw: KeyFinderWorkspacePtr,
+
+    // the length of a timeline block in frames (0 until the frame rate is known), the
+    // frames added to the current block and the length and key of the finished ones
+    block_frames: usize,
+    frames_in_block: usize,
+    blocks: Vec<(usize, SongKey)>,
+}
+
+// Like the audio data, the workspace is only used by one key finder call at a time
+unsafe impl Send for LibWorkspace {}
+
+impl Default for LibWorkspace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LibWorkspace {
+    pub fn new() -> Self {
+        LibWorkspace {
+            raw: unsafe { kfwrapper__init_workspace() },
+            block_raw: unsafe { kfwrapper__init_workspace() },
+            block_frames: 0,
+            frames_in_block: 0,
+            blocks: vec![],
+        }
+    }
+
+    // Finds the key of the current timeline block and starts a new one
+    fn finish_block(&mut self) {
+        if self.frames_in_block == 0 {
+            return;
+        }
+        let key = unsafe {
+            kfwrapper__final_chromagram(self.block_raw);
+            kfwrapper__key_of_chromagram(self.block_raw)
+        };
+        self.blocks
+            .push((self.frames_in_block, SongKey::from_key_t(key)));
+
+        unsafe {
+            kfwrapper__destroy_workspace(self.block_raw);
+            self.block_raw = kfwrapper__init_workspace();
+        }
+        self.frames_in_block = 0;
+    }
+}
+
+impl Drop for LibWorkspace {
+    fn drop(&mut self) {
+        unsafe {
+            kfwrapper__destroy_workspace(self.raw);
+            kfwrapper__destroy_workspace(self.block_raw);
+        }
+    }
+}
+
+// The wrapper keeps a shared libkeyfinder instance, so this is only a handle to it and
+// the tone profile the key scores are computed with
+#[derive(Default)]
+pub struct LibKeyFinder {
+    tone_profile: ToneProfile,
+}
+
+impl LibKeyFinder {
+    pub fn new() -> Self {
+        LibKeyFinder {
+            tone_profile: ToneProfile::default(),
+        }
+    }
+
+    // Sets the templates the key scores are computed with. libkeyfinder picks the key with
+    // its own tone profile built in.
+    pub fn set_tone_profile(&mut self, tone_profile: ToneProfile) {
+        self.tone_profile = tone_profile;
+    }
+
+    // Returns the key of the audio
+    pub fn key_of_audio(&mut self, audio_data: &LibAudioData) -> SongKey {
+        SongKey::from_key_t(unsafe { kfwrapper__key_of_audio(audio_data.raw) })
+    }
+
+    // Adds the chromagram of a block of audio to the workspace. Timeline blocks end at the
+    // first audio block boundary after `TIMELINE_BLOCK_SECS`.
+    pub fn progressive_chromagram(
+        &mut self,
+        audio_data: &LibAudioData,
+        workspace: &mut LibWorkspace,
+    ) {
+        if workspace.block_frames == 0 {
+            workspace.block_frames =
+                ((super::TIMELINE_BLOCK_SECS * audio_data.frame_rate() as f64) as usize).max(1);
+        }
+
+        unsafe {
+            kfwrapper__progressive_chromagram(audio_data.raw, workspace.raw);
+            kfwrapper__progressive_chromagram(audio_data.raw, workspace.block_raw);
+        }
+        workspace.frames_in_block += audio_data.frame_count();
+        if workspace.frames_in_block >= workspace.block_frames {
+            workspace.finish_block();
+        }
+    }
+
+    // Analyses the audio still buffered in the workspace, call it once the stream ends
+    pub fn final_chromagram(&mut self, workspace: &mut LibWorkspace) {
+        unsafe { kfwrapper__final_chromagram(workspace.raw) }
+        workspace.finish_block();
+    }
+
+    // Returns the key of the chromagram collected so far
+    pub fn key_of_chromagram(&self, workspace: &LibWorkspace) -> SongKey {
+        SongKey::from_key_t(unsafe { kfwrapper__key_of_chromagram(workspace.raw) })
+    }
+
+    // Returns how well the chromagram collected so far matches each key, best match first.
+    // libkeyfinder only exposes its best key, so the chroma vector of its chromagram is
+    // scored against the native key templates.
+    pub fn key_scores_of_chromagram(&self, workspace: &LibWorkspace) -> Vec<KeyScore> {
+        let mut chroma = [0.0f64; 12];
+        if !unsafe { kfwrapper__chroma_of_chromagram(workspace.raw, chroma.as_mut_ptr()) } {
+            return vec![];
+        }
+        super::key_scores_of_chroma(self.tone_profile, &chroma.map(|value| value as f32))
+    }
+
+    // Returns the length in frames and the key of every finished timeline block
+    pub fn key_timeline(&self, workspace: &LibWorkspace) -> Vec<(usize, SongKey)> {
+        workspace.blocks.clone()
+    }
+}
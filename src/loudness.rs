@@ -0,0 +1,469 @@
+// Loudness and energy
+// -------------------
+//
+// Integrated loudness and loudness range follow ITU-R BS.1770-4 and EBU Tech 3342: the
+// channels are K-weighted (a high shelf for the head and a high-pass for the lowest
+// bass), their mean square is summed up in 100ms steps and the gated average of the
+// overlapping 400ms (integrated) or 3s (range) windows is converted to LUFS. The true
+// peak is the highest sample after 4x oversampling, which catches the inter-sample peaks
+// a DAC would produce.
+//
+// Besides that, the plain RMS of every 100ms is kept as an energy envelope: condensed
+// into the energy curve stored with the song, and used to find intros, breakdowns and
+// drops.
+
+use serde::{Deserialize, Serialize};
+
+// The length of a loudness step in seconds
+pub const STEP_SECS: f64 = 0.1;
+
+// The number of steps in the windows of integrated loudness and loudness range
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+// Windows quieter than this are silence, left out of every average
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+// Windows this far below the (absolute gated) average are left out of the integrated
+// loudness and the loudness range
+const INTEGRATED_RELATIVE_GATE_LU: f64 = -10.0;
+const RANGE_RELATIVE_GATE_LU: f64 = -20.0;
+
+// The energy curve stored with a song has a point for every second, or fewer for very
+// long audio
+const CURVE_SECS: f64 = 1.0;
+const MAX_CURVE_POINTS: usize = 600;
+
+// The number of taps of each phase of the true peak interpolation filter
+const TRUE_PEAK_TAPS: usize = 12;
+
+// The RMS level used for silence, so the envelope stays finite
+const SILENCE_DB: f32 = -120.0;
+
+// The loudness of a track
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loudness {
+    pub integrated_lufs: f64,
+    // The spread between soft and loud passages, in LU
+    pub loudness_range: f64,
+    pub true_peak_dbtp: f64,
+    // From 1 (quiet and dynamic, like ambient) to 10 (loud and compressed, like peak time
+    // techno)
+    pub energy_level: u8,
+    pub energy_curve: EnergyCurve,
+}
+
+// The RMS level of a track over time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnergyCurve {
+    // The length each point covers
+    pub interval_secs: f64,
+    // The RMS level of each interval in dBFS
+    pub rms_db: Vec<f32>,
+}
+
+// A biquad filter in direct form II transposed
+#[derive(Debug, Copy, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// Returns the two K-weighting stages for a frame rate (the BS.1770 filters, derived for
+// any rate rather than only the 48kHz coefficients of the standard)
+fn k_weighting(frame_rate: u32) -> [Biquad; 2] {
+    use std::f64::consts::PI;
+    let rate = frame_rate as f64;
+
+    // the high shelf modelling the head
+    let k = (PI * 1681.974450955533 / rate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    // the high-pass leaving out the lowest bass
+    let k = (PI * 38.13547087602444 / rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+// Returns the windowed sinc interpolation filter for oversampling by `factor`, with the
+// taps of each phase next to each other
+fn interpolation_filter(factor: usize) -> Vec<Vec<f64>> {
+    use std::f64::consts::PI;
+
+    let length = TRUE_PEAK_TAPS * factor;
+    let centre = (length - 1) as f64 / 2.0;
+    let tap = |n: usize| {
+        let x = (n as f64 - centre) / factor as f64;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * (n as f64 + 0.5) / length as f64).cos();
+        sinc * window
+    };
+
+    (0..factor)
+        .map(|phase| {
+            (0..TRUE_PEAK_TAPS)
+                .map(|i| tap(i * factor + phase))
+                .collect()
+        })
+        .collect()
+}
+
+// The filters and history of one channel
+struct ChannelState {
+    k_weighting: [Biquad; 2],
+    // the last samples, newest first, for the true peak interpolation
+    history: Vec<f64>,
+}
+
+// Streams decoded audio into the loudness measurements
+pub struct LoudnessMeter {
+    frame_rate: u32,
+    step_frames: usize,
+    channels: Vec<ChannelState>,
+    oversampling: Vec<Vec<f64>>,
+
+    // the sums of the current step: the weighted K-weighted mean square, the plain mean
+    // square and the number of frames
+    weighted_sum: f64,
+    square_sum: f64,
+    frames_in_step: usize,
+
+    // the finished steps: the K-weighted power and the RMS level in dBFS
+    powers: Vec<f64>,
+    rms_db: Vec<f32>,
+
+    true_peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(frame_rate: u32) -> Self {
+        // higher rates need less oversampling to catch the same peaks
+        let factor = match frame_rate {
+            0..=95_999 => 4,
+            96_000..=191_999 => 2,
+            _ => 1,
+        };
+
+        LoudnessMeter {
+            frame_rate,
+            step_frames: ((frame_rate as f64 * STEP_SECS) as usize).max(1),
+            channels: vec![],
+            oversampling: interpolation_filter(factor),
+            weighted_sum: 0.0,
+            square_sum: 0.0,
+            frames_in_step: 0,
+            powers: vec![],
+            rms_db: vec![],
+            true_peak: 0.0,
+        }
+    }
+
+    // The RMS level in dBFS of every `STEP_SECS` measured so far
+    pub fn rms_envelope(&self) -> &[f32] {
+        &self.rms_db
+    }
+
+    // K-weights every channel of a block of planar samples (the channels are kept apart
+    // for their BS.1770 weights and the true peak) and closes the steps it completes
+    pub fn add_planar_samples(&mut self, samples: &[f32], channel_count: usize) {
+        if channel_count == 0 {
+            return;
+        }
+        if self.channels.len() != channel_count {
+            self.channels = (0..channel_count)
+                .map(|_| ChannelState {
+                    k_weighting: k_weighting(self.frame_rate),
+                    history: vec![0.0; TRUE_PEAK_TAPS],
+                })
+                .collect();
+        }
+
+        let frames = samples.len() / channel_count;
+        for frame in 0..frames {
+            for (channel, state) in self.channels.iter_mut().enumerate() {
+                let x = samples[channel * frames + frame] as f64;
+
+                let weighted = state
+                    .k_weighting
+                    .iter_mut()
+                    .fold(x, |x, stage| stage.process(x));
+                self.weighted_sum += channel_weight(channel, channel_count) * weighted * weighted;
+                self.square_sum += x * x;
+
+                state.history.rotate_right(1);
+                state.history[0] = x;
+                for phase in &self.oversampling {
+                    let interpolated: f64 = phase
+                        .iter()
+                        .zip(state.history.iter())
+                        .map(|(tap, x)| tap * x)
+                        .sum();
+                    self.true_peak = self.true_peak.max(interpolated.abs());
+                }
+                self.true_peak = self.true_peak.max(x.abs());
+            }
+
+            self.frames_in_step += 1;
+            if self.frames_in_step == self.step_frames {
+                let frames = self.frames_in_step as f64;
+                self.powers.push(self.weighted_sum / frames);
+                let mean_square = self.square_sum / (frames * channel_count as f64);
+                self.rms_db.push(db(mean_square.sqrt()));
+
+                self.weighted_sum = 0.0;
+                self.square_sum = 0.0;
+                self.frames_in_step = 0;
+            }
+        }
+    }
+
+    // Returns the loudness of the whole stream, or nothing if it is silent or shorter
+    // than a single 400ms window
+    pub fn finish(&self) -> Option<Loudness> {
+        let integrated_lufs = gated_loudness(
+            &window_powers(&self.powers, MOMENTARY_STEPS),
+            INTEGRATED_RELATIVE_GATE_LU,
+        )?;
+
+        let loudness_range = {
+            let short_term = window_powers(&self.powers, SHORT_TERM_STEPS);
+            match gated_loudness(&short_term, RANGE_RELATIVE_GATE_LU) {
+                Some(average) => {
+                    let mut levels: Vec<f64> = short_term
+                        .iter()
+                        .map(|power| lufs(*power))
+                        .filter(|level| {
+                            *level > ABSOLUTE_GATE_LUFS && *level > average + RANGE_RELATIVE_GATE_LU
+                        })
+                        .collect();
+                    levels.sort_by(|a, b| a.total_cmp(b));
+                    percentile(&levels, 0.95) - percentile(&levels, 0.10)
+                }
+                // too short for a 3s window, which has no range to speak of
+                None => 0.0,
+            }
+        };
+
+        Some(Loudness {
+            integrated_lufs: round(integrated_lufs, 10.0),
+            loudness_range: round(loudness_range, 10.0),
+            true_peak_dbtp: round(20.0 * self.true_peak.max(1e-10).log10(), 10.0),
+            energy_level: energy_level(integrated_lufs, loudness_range),
+            energy_curve: self.energy_curve(),
+        })
+    }
+
+    // Condenses the RMS envelope into at most `MAX_CURVE_POINTS` whole second intervals
+    fn energy_curve(&self) -> EnergyCurve {
+        let secs = self.rms_db.len() as f64 * STEP_SECS;
+        let interval_secs = CURVE_SECS.max((secs / MAX_CURVE_POINTS as f64).ceil());
+        let steps = (interval_secs / STEP_SECS).round() as usize;
+
+        EnergyCurve {
+            interval_secs,
+            rms_db: self
+                .rms_db
+                .chunks(steps)
+                .map(|chunk| {
+                    // average the power, not the levels
+                    let power = chunk
+                        .iter()
+                        .map(|level| 10f64.powf(*level as f64 / 10.0))
+                        .sum::<f64>()
+                        / chunk.len() as f64;
+                    (db(power.sqrt()) * 10.0).round() / 10.0
+                })
+                .collect(),
+        }
+    }
+}
+
+// The BS.1770 weight of a channel: the surround channels of 5.1 and up count more, and
+// the LFE channel (the fourth, in the usual L R C LFE Ls Rs order) isn't counted at all
+fn channel_weight(channel: usize, channel_count: usize) -> f64 {
+    match (channel, channel_count) {
+        (_, 0..=4) => 1.0,
+        (0..=2, _) => 1.0,
+        (3, _) => 0.0,
+        _ => 1.41,
+    }
+}
+
+// The mean power of every window of `steps` steps, moving one step at a time
+fn window_powers(powers: &[f64], steps: usize) -> Vec<f64> {
+    powers
+        .windows(steps)
+        .map(|window| window.iter().sum::<f64>() / steps as f64)
+        .collect()
+}
+
+// The loudness of the average power of the windows passing the absolute gate and then
+// the relative gate, or nothing if none pass
+fn gated_loudness(powers: &[f64], relative_gate: f64) -> Option<f64> {
+    let average = |threshold: f64| -> Option<f64> {
+        let gated: Vec<f64> = powers
+            .iter()
+            .copied()
+            .filter(|power| lufs(*power) > threshold)
+            .collect();
+        if gated.is_empty() {
+            None
+        } else {
+            Some(gated.iter().sum::<f64>() / gated.len() as f64)
+        }
+    };
+
+    let absolute = average(ABSOLUTE_GATE_LUFS)?;
+    average(lufs(absolute) + relative_gate).map(lufs)
+}
+
+// The loudness of a K-weighted power
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.max(1e-20).log10()
+}
+
+// The level of an amplitude in dB
+fn db(amplitude: f64) -> f32 {
+    if amplitude <= 0.0 {
+        SILENCE_DB
+    } else {
+        (20.0 * amplitude.log10()).max(SILENCE_DB as f64) as f32
+    }
+}
+
+// The value at a fraction of the sorted values (nearest rank)
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+// Rates a track from 1 to 10: mostly by how loud it is (-30 LUFS and below being the
+// quietest, -6 LUFS the loudest), and a little by how compressed (little loudness range)
+fn energy_level(integrated_lufs: f64, loudness_range: f64) -> u8 {
+    let loudness = ((integrated_lufs + 30.0) / 24.0).clamp(0.0, 1.0);
+    let density = (1.0 - loudness_range / 20.0).clamp(0.0, 1.0);
+    1 + (9.0 * (0.7 * loudness + 0.3 * density)).round() as u8
+}
+
+fn round(value: f64, scale: f64) -> f64 {
+    (value * scale).round() / scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_RATE: u32 = 48000;
+
+    // Measures a stereo sine at the given peak levels (in dBFS), each held for `secs`
+    fn measure_sine(levels_db: &[f64], secs: f64) -> Option<Loudness> {
+        let mut meter = LoudnessMeter::new(FRAME_RATE);
+        let block_frames = FRAME_RATE as usize / 10;
+        let mut frame = 0usize;
+        for level_db in levels_db {
+            let amplitude = 10f64.powf(level_db / 20.0);
+            for _ in 0..(secs * 10.0) as usize {
+                let channel: Vec<f32> = (frame..frame + block_frames)
+                    .map(|i| {
+                        let t = i as f64 / FRAME_RATE as f64;
+                        (amplitude * (2.0 * std::f64::consts::PI * 997.0 * t).sin()) as f32
+                    })
+                    .collect();
+                meter.add_planar_samples(&[channel.clone(), channel].concat(), 2);
+                frame += block_frames;
+            }
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn ebu_tech_3341_stereo_sine() {
+        let loudness = measure_sine(&[-23.0], 10.0).unwrap();
+        assert!(
+            (loudness.integrated_lufs + 23.0).abs() <= 0.1,
+            "{:?}",
+            loudness
+        );
+        assert!(loudness.loudness_range <= 0.1);
+        assert!((loudness.true_peak_dbtp + 23.0).abs() <= 0.2);
+    }
+
+    #[test]
+    fn ebu_tech_3342_loudness_range() {
+        let loudness = measure_sine(&[-20.0, -30.0, -20.0], 10.0).unwrap();
+        assert!(
+            (loudness.loudness_range - 10.0).abs() <= 1.0,
+            "{:?}",
+            loudness
+        );
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = LoudnessMeter::new(FRAME_RATE);
+        meter.add_planar_samples(&vec![0.0; FRAME_RATE as usize * 4], 2);
+        assert_eq!(meter.finish(), None);
+        assert!(meter
+            .rms_envelope()
+            .iter()
+            .all(|level| *level == SILENCE_DB));
+    }
+
+    #[test]
+    fn energy_level_bounds() {
+        assert_eq!(energy_level(-100.0, 50.0), 1);
+        assert_eq!(energy_level(10.0, 0.0), 10);
+
+        // louder and more compressed is never less energetic
+        let mut previous = 1;
+        for lufs in (-40..=0).map(f64::from) {
+            let level = energy_level(lufs, 5.0);
+            assert!((1..=10).contains(&level));
+            assert!(level >= previous);
+            assert!(energy_level(lufs, 2.0) >= energy_level(lufs, 15.0));
+            previous = level;
+        }
+    }
+}
@@ -1,61 +1,18 @@
 use serde::{Deserialize, Serialize};
 
-#[macro_use(defer)]
-extern crate scopeguard;
-
-pub struct KeyFinder {
-    // TODO: state goes here
-}
-
-impl KeyFinder {
-    pub fn new() -> Self {
-        KeyFinder {}
-    }
-
-    pub fn set_frame_rate(&mut self, _frame_rate: u32) {}
-}
-
-// use a type alias so we can change this later for opaque struct
-type KeyFinderAudioDataPtr = *mut ::libc::c_void;
-
-/*
-
-extern "C" {
-
-    // intializer for the audio data
-    pub fn kfwrapper__init_audio_data(frame_rate: u32) -> KeyFinderAudioDataPtr;
-
-    // destructor for the audio data
-    pub fn kfwrapper__destroy_audio_data(audio_data: KeyFinderAudioDataPtr);
-
-    // add a number of samples to the audio data
-    pub fn kfwrapper__add_to_samples(audio_data: KeyFinderAudioDataPtr, data: *const f32, data_size: u64 );
-
-    // returns the current key of the audio data
-    pub fn kfwrapper__key_of_audio(audio_data: KeyFinderAudioDataPtr) -> i32;
-
-}
-*/
-// intializer for the audio data
-pub fn kfwrapper__init_audio_data(frame_rate: u32) -> KeyFinderAudioDataPtr {
-    std::ptr::null_mut()
-}
-
-// destructor for the audio data
-pub fn kfwrapper__destroy_audio_data(audio_data: KeyFinderAudioDataPtr) {}
-
-// add a number of samples to the audio data
-pub fn kfwrapper__add_to_samples(
-    audio_data: KeyFinderAudioDataPtr,
-    data: *const f32,
-    data_size: u64,
-) {
-}
-
-// returns the current key of the audio data
-pub fn kfwrapper__key_of_audio(audio_data: KeyFinderAudioDataPtr) -> i32 {
-    1
-}
+pub mod cues;
+pub mod keyfinder;
+pub mod loudness;
+pub mod tags;
+pub mod tempo;
+pub mod wavmeta;
+
+use cues::CuePoint;
+use keyfinder::{
+    ChannelAudio, ChannelMode, KeyFinder, KeyScore, KeySegment, ToneProfile, MIN_AUDIO_SECS,
+};
+use loudness::{EnergyCurve, LoudnessMeter};
+use tempo::{BeatGrid, BpmRange, TempoAnalyser};
 
 /*
 
@@ -80,7 +37,7 @@ KeyFinder::key_t key = k.keyOfAudio(a);
 
 */
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub enum SongKey {
     CMaj,
     DfMaj,
@@ -108,94 +65,249 @@ pub enum SongKey {
     BfMin,
     BMin,
 
+    #[default]
     Unknown,
 }
 
-impl std::convert::From<String> for SongKey {
-    fn from(s: String) -> Self {
-        match s.to_lowercase().as_str() {
-            "AMaj" => SongKey::AMaj,
-            "AMin" => SongKey::AMin,
-
-            "BfMaj" => SongKey::BfMaj,
-            "BfMin" => SongKey::BfMin,
-
-            "BMaj" => SongKey::BMaj,
-            "BMin" => SongKey::BMin,
-
-            "CMaj" => SongKey::CMaj,
-            "CMin" => SongKey::CMin,
-
-            "DfMaj" => SongKey::DfMaj,
-            "DfMin" => SongKey::DfMin,
-
-            "DMaj" => SongKey::DMaj,
-            "DMin" => SongKey::DMin,
-
-            "EfMaj" => SongKey::EfMaj,
-            "EfMin" => SongKey::EfMin,
+// The error returned when a string cannot be parsed into a SongKey
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSongKeyError {
+    input: String,
+    reason: &'static str,
+}
 
-            "EMaj" => SongKey::EMaj,
-            "EMin" => SongKey::EMin,
+impl std::fmt::Display for ParseSongKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid key \"{}\": {}", self.input, self.reason)
+    }
+}
 
-            "FMaj" => SongKey::FMaj,
-            "FMin" => SongKey::FMin,
+impl std::error::Error for ParseSongKeyError {}
+
+// Parses a key from any of the common notations:
+//
+// - the enum names: `AMin`, `BfMaj`
+// - Camelot: `8A`, `11B`
+// - Open Key: `1m`, `6d`
+// - musical notation: `A minor`, `Am`, `C#m`, `Bbmaj`, `F♯`, `E flat minor`
+impl std::str::FromStr for SongKey {
+    type Err = ParseSongKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ParseSongKeyError {
+            input: String::from(s),
+            reason,
+        };
 
-            "GfMaj" => SongKey::GfMaj,
-            "GfMin" => SongKey::GfMin,
+        let input = s.trim();
+        if input.is_empty() {
+            return Err(error("the key is empty"));
+        }
 
-            "GMaj" => SongKey::GMaj,
-            "GMin" => SongKey::GMin,
+        // enum names, as used in the index
+        if let Some(key) = SongKey::ALL
+            .into_iter()
+            .chain([SongKey::Unknown])
+            .find(|key| format!("{:?}", key).eq_ignore_ascii_case(input))
+        {
+            return Ok(key);
+        }
 
-            "AfMaj" => SongKey::AfMaj,
-            "AfMin" => SongKey::AfMin,
+        // Camelot and Open Key both start with the wheel number
+        if input.starts_with(|c: char| c.is_ascii_digit()) {
+            let split = input
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(input.len());
+            let (number, letter) = input.split_at(split);
+            let number: u32 = number
+                .parse()
+                .map_err(|_| error("the wheel number is not a number"))?;
+            if !(1..=12).contains(&number) {
+                return Err(error("wheel numbers go from 1 to 12"));
+            }
 
-            _ => SongKey::Unknown,
+            return match letter {
+                "A" | "a" => Ok(SongKey::from_camelot_position(number, true)),
+                "B" | "b" => Ok(SongKey::from_camelot_position(number, false)),
+                // Open Key starts at C major / A minor, which is 8 on the Camelot wheel
+                "m" | "M" => Ok(SongKey::from_camelot_position(
+                    camelot_step(number, 7),
+                    true,
+                )),
+                "d" | "D" => Ok(SongKey::from_camelot_position(
+                    camelot_step(number, 7),
+                    false,
+                )),
+                _ => Err(error(
+                    "expected a Camelot (8A, 8B) or Open Key (1m, 1d) letter after the number",
+                )),
+            };
         }
+
+        parse_musical_key(input).ok_or_else(|| {
+            error("expected a key name (AMin), Camelot (8A), Open Key (1m) or musical notation (A minor, C#m, Bbmaj)")
+        })
     }
 }
 
-impl SongKey {
-    // Converts a LibKeyFinder key_t into a SongKey
-    pub fn from_key_t(i: i32) -> SongKey {
-        match i {
-            0 => SongKey::AMaj,
-            1 => SongKey::AMin,
+// Parses musical notation: a note letter, any number of accidentals and an optional mode
+fn parse_musical_key(input: &str) -> Option<SongKey> {
+    let mut chars = input.chars();
+    let mut pitch_class: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
 
-            2 => SongKey::BfMaj,
-            3 => SongKey::BfMin,
+    // accidentals
+    let mut rest = chars.as_str();
+    loop {
+        let trimmed = rest.trim_start_matches([' ', '-']);
+        if let Some(r) = trimmed
+            .strip_prefix("sharp")
+            .or_else(|| rest.strip_prefix('#'))
+            .or_else(|| rest.strip_prefix('♯'))
+        {
+            pitch_class += 1;
+            rest = r;
+        } else if let Some(r) = trimmed
+            .strip_prefix("flat")
+            .or_else(|| rest.strip_prefix('b'))
+            .or_else(|| rest.strip_prefix('♭'))
+        {
+            pitch_class -= 1;
+            rest = r;
+        } else {
+            break;
+        }
+    }
 
-            4 => SongKey::BMaj,
-            5 => SongKey::BMin,
+    // the mode, defaulting to major
+    let minor = match rest.trim_start_matches([' ', '-', '_']) {
+        "" | "M" => false,
+        "m" => true,
+        mode => match mode.to_lowercase().as_str() {
+            "maj" | "major" => false,
+            "min" | "minor" => true,
+            _ => return None,
+        },
+    };
 
-            6 => SongKey::CMaj,
-            7 => SongKey::CMin,
+    Some(SongKey::from_pitch_class(
+        pitch_class.rem_euclid(12) as usize,
+        minor,
+    ))
+}
 
-            8 => SongKey::DfMaj,
-            9 => SongKey::DfMin,
+// The error returned for integers that are not a LibKeyFinder key_t
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidKeyT(pub i32);
 
-            10 => SongKey::DMaj,
-            11 => SongKey::DMin,
+impl std::fmt::Display for InvalidKeyT {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not a LibKeyFinder key_t (0-24)", self.0)
+    }
+}
 
-            12 => SongKey::EfMaj,
-            13 => SongKey::EfMin,
+impl std::error::Error for InvalidKeyT {}
 
-            14 => SongKey::EMaj,
-            15 => SongKey::EMin,
+// Converts a LibKeyFinder key_t into a SongKey, with SILENCE becoming Unknown
+impl std::convert::TryFrom<i32> for SongKey {
+    type Error = InvalidKeyT;
 
-            16 => SongKey::FMaj,
-            17 => SongKey::FMin,
+    fn try_from(i: i32) -> Result<Self, Self::Error> {
+        match i {
+            0..=23 => Ok(SongKey::KEY_T_ORDER[i as usize]),
+            SongKey::KEY_T_SILENCE => Ok(SongKey::Unknown),
+            _ => Err(InvalidKeyT(i)),
+        }
+    }
+}
 
-            18 => SongKey::GfMaj,
-            19 => SongKey::GfMin,
+impl SongKey {
+    // All 24 keys in declaration order: majors from C, then minors from C
+    pub const ALL: [SongKey; 24] = [
+        SongKey::CMaj,
+        SongKey::DfMaj,
+        SongKey::DMaj,
+        SongKey::EfMaj,
+        SongKey::EMaj,
+        SongKey::FMaj,
+        SongKey::GfMaj,
+        SongKey::GMaj,
+        SongKey::AfMaj,
+        SongKey::AMaj,
+        SongKey::BfMaj,
+        SongKey::BMaj,
+        SongKey::CMin,
+        SongKey::DfMin,
+        SongKey::DMin,
+        SongKey::EfMin,
+        SongKey::EMin,
+        SongKey::FMin,
+        SongKey::GfMin,
+        SongKey::GMin,
+        SongKey::AfMin,
+        SongKey::AMin,
+        SongKey::BfMin,
+        SongKey::BMin,
+    ];
+
+    // The keys in the order of the LibKeyFinder key_t enum (see `constants.h`), which
+    // starts from A and alternates major and minor
+    const KEY_T_ORDER: [SongKey; 24] = [
+        SongKey::AMaj,
+        SongKey::AMin,
+        SongKey::BfMaj,
+        SongKey::BfMin,
+        SongKey::BMaj,
+        SongKey::BMin,
+        SongKey::CMaj,
+        SongKey::CMin,
+        SongKey::DfMaj,
+        SongKey::DfMin,
+        SongKey::DMaj,
+        SongKey::DMin,
+        SongKey::EfMaj,
+        SongKey::EfMin,
+        SongKey::EMaj,
+        SongKey::EMin,
+        SongKey::FMaj,
+        SongKey::FMin,
+        SongKey::GfMaj,
+        SongKey::GfMin,
+        SongKey::GMaj,
+        SongKey::GMin,
+        SongKey::AfMaj,
+        SongKey::AfMin,
+    ];
+
+    // The key_t LibKeyFinder returns for silent audio
+    const KEY_T_SILENCE: i32 = 24;
 
-            20 => SongKey::GMaj,
-            21 => SongKey::GMin,
+    // Converts a LibKeyFinder key_t into a SongKey
+    pub fn from_key_t(i: i32) -> SongKey {
+        SongKey::try_from(i).unwrap_or(SongKey::Unknown)
+    }
 
-            22 => SongKey::AfMaj,
-            23 => SongKey::AfMin,
+    // Converts the key into a LibKeyFinder key_t, with Unknown becoming SILENCE
+    pub fn to_key_t(&self) -> i32 {
+        match SongKey::KEY_T_ORDER.iter().position(|key| key == self) {
+            Some(i) => i as i32,
+            None => SongKey::KEY_T_SILENCE,
+        }
+    }
 
-            _ => SongKey::Unknown,
+    // Converts a tonic pitch class (C = 0, C# = 1, ...) and mode into a SongKey
+    pub fn from_pitch_class(pitch_class: usize, minor: bool) -> SongKey {
+        match minor {
+            false => SongKey::ALL[pitch_class % 12],
+            true => SongKey::ALL[12 + pitch_class % 12],
         }
     }
 
@@ -242,121 +354,760 @@ impl SongKey {
         })
     }
 
-    // returns a list of compatible keys
-    // TODO: fill this matrix
-    pub fn compatible_keys(&self) -> Vec<SongKey> {
-        match self {
-            Self::AMaj => vec![],
-            Self::AMin => vec![
-                SongKey::CMaj,
-                SongKey::AMin,
-                SongKey::DMin,
-                SongKey::FMaj,
-                SongKey::EMin,
-                SongKey::GMaj,
-            ],
+    // Converts the key to Open Key notation (as used by Traktor), where C major is 1d
+    // and A minor is 1m
+    pub fn to_open_key(&self) -> String {
+        match self.camelot_position() {
+            Some((number, minor)) => format!(
+                "{}{}",
+                camelot_step(number, -7),
+                if minor { "m" } else { "d" }
+            ),
+            None => String::from("Unknown"),
+        }
+    }
+
+    // Converts the key to its musical name, like "A♭ minor" or "G♯ minor"
+    pub fn to_musical_name(&self, accidental: Accidental) -> String {
+        const SHARP_NAMES: [&str; 12] = [
+            "C", "C♯", "D", "D♯", "E", "F", "F♯", "G", "G♯", "A", "A♯", "B",
+        ];
+        const FLAT_NAMES: [&str; 12] = [
+            "C", "D♭", "D", "E♭", "E", "F", "G♭", "G", "A♭", "A", "B♭", "B",
+        ];
+
+        let (pitch_class, minor) = match self.tonic() {
+            Some(tonic) => tonic,
+            None => return String::from("Unknown"),
+        };
+
+        let tonic_name = match accidental {
+            Accidental::Sharp => SHARP_NAMES[pitch_class],
+            Accidental::Flat => FLAT_NAMES[pitch_class],
+        };
+
+        format!("{} {}", tonic_name, if minor { "minor" } else { "major" })
+    }
+
+    // Converts the key to the requested notation
+    pub fn to_notation(&self, notation: KeyNotation) -> String {
+        match notation {
+            KeyNotation::Name => format!("{:?}", self),
+            KeyNotation::Camelot => self.to_circle_of_fifths(),
+            KeyNotation::OpenKey => self.to_open_key(),
+            KeyNotation::Musical => self.to_musical_name(Accidental::Flat),
+            KeyNotation::MusicalSharps => self.to_musical_name(Accidental::Sharp),
+        }
+    }
+
+    // Returns the tonic pitch class (C = 0) and whether the key is minor. Relies on the
+    // declaration order of the variants, the same as `SongKey::ALL`.
+    fn tonic(&self) -> Option<(usize, bool)> {
+        match *self {
+            Self::Unknown => None,
+            key => Some((key as usize % 12, key as usize >= 12)),
+        }
+    }
 
-            Self::BfMaj => vec![],
-            Self::BfMin => vec![],
+    // Returns the pitch class of the tonic (C = 0, C# = 1, ...)
+    pub fn tonic_pitch_class(&self) -> Option<usize> {
+        self.tonic().map(|(pitch_class, _)| pitch_class)
+    }
 
-            Self::BMaj => vec![],
-            Self::BMin => vec![],
+    pub fn mode(&self) -> Option<Mode> {
+        self.tonic().map(|(_, minor)| match minor {
+            false => Mode::Major,
+            true => Mode::Minor,
+        })
+    }
 
-            Self::CMaj => vec![],
-            Self::CMin => vec![],
+    // Moves the key up (or down, for negative values) by a number of semitones, like
+    // pitching a track with the tempo fader does
+    pub fn transpose(&self, semitones: i32) -> SongKey {
+        match self.tonic() {
+            Some((pitch_class, minor)) => SongKey::from_pitch_class(
+                (pitch_class as i32 + semitones).rem_euclid(12) as usize,
+                minor,
+            ),
+            None => SongKey::Unknown,
+        }
+    }
 
-            Self::DfMaj => vec![],
-            Self::DfMin => vec![],
+    // Returns the relative major / minor, which shares the same notes (A minor <-> C major)
+    pub fn relative(&self) -> SongKey {
+        match self.tonic() {
+            Some((pitch_class, false)) => SongKey::from_pitch_class(pitch_class + 9, true),
+            Some((pitch_class, true)) => SongKey::from_pitch_class(pitch_class + 3, false),
+            None => SongKey::Unknown,
+        }
+    }
 
-            Self::DMaj => vec![],
-            Self::DMin => vec![],
+    // Returns the parallel major / minor, which shares the same tonic (A minor <-> A major)
+    pub fn parallel(&self) -> SongKey {
+        match self.tonic() {
+            Some((pitch_class, minor)) => SongKey::from_pitch_class(pitch_class, !minor),
+            None => SongKey::Unknown,
+        }
+    }
 
-            Self::EfMaj => vec![],
-            Self::EfMin => vec![],
+    // Returns the position of the key on the Camelot wheel: the number (1-12) and
+    // whether it is a minor ("A") key
+    pub fn camelot_position(&self) -> Option<(u32, bool)> {
+        let (pitch_class, minor) = self.tonic()?;
 
-            Self::EMaj => vec![],
-            Self::EMin => vec![],
+        // minor keys share the number of their relative major, three semitones up
+        let major_pitch_class = match minor {
+            false => pitch_class,
+            true => (pitch_class + 3) % 12,
+        };
 
-            Self::FMaj => vec![],
-            Self::FMin => vec![],
+        // every step clockwise is a fifth (7 semitones) up, starting from C major at 8B
+        let fifths = (major_pitch_class * 7 % 12) as u32;
+        Some(((fifths + 7) % 12 + 1, minor))
+    }
 
-            Self::GfMaj => vec![],
-            Self::GfMin => vec![],
+    // Converts a Camelot wheel position (number 1-12, minor) back into a SongKey
+    pub fn from_camelot_position(number: u32, minor: bool) -> SongKey {
+        if !(1..=12).contains(&number) {
+            return SongKey::Unknown;
+        }
 
-            Self::GMaj => vec![],
-            Self::GMin => vec![],
+        // 7 is its own inverse modulo 12, so this walks the fifths back to a pitch class
+        let fifths = (number as usize + 4) % 12;
+        let major_pitch_class = fifths * 7 % 12;
 
-            Self::AfMaj => vec![],
-            Self::AfMin => vec![],
+        match minor {
+            false => SongKey::from_pitch_class(major_pitch_class, false),
+            true => SongKey::from_pitch_class(major_pitch_class + 9, true),
+        }
+    }
 
-            Self::Unknown => vec![],
+    // returns a list of compatible keys: the key itself, its relative major / minor
+    // (same number) and its neighbours on the wheel (one number lower and higher)
+    pub fn compatible_keys(&self) -> Vec<SongKey> {
+        self.compatible_keys_with(MixRule::BASIC)
+            .into_iter()
+            .map(|compatible| compatible.key)
+            .collect()
+    }
+
+    // returns the keys reachable from this key with any of the rules, tagged with the
+    // first rule that produced them
+    pub fn compatible_keys_with(&self, rules: &[MixRule]) -> Vec<CompatibleKey> {
+        let mut compatible_keys: Vec<CompatibleKey> = vec![];
+
+        for &rule in rules {
+            for key in self.apply_mix_rule(rule) {
+                if !compatible_keys
+                    .iter()
+                    .any(|compatible| compatible.key == key)
+                {
+                    compatible_keys.push(CompatibleKey { key, rule });
+                }
+            }
         }
+
+        compatible_keys
     }
+
+    // returns the keys that become compatible when this track is pitched by at most
+    // `max_semitones` up or down. Every key is listed once, with the smallest pitch
+    // change that reaches it.
+    pub fn compatible_keys_when_pitched(
+        &self,
+        max_semitones: u32,
+        rules: &[MixRule],
+    ) -> Vec<PitchedKey> {
+        let max_semitones = max_semitones.min(11) as i32;
+        let mut pitched_keys: Vec<PitchedKey> = vec![];
+
+        // try the smaller pitch changes first, so they win for keys reachable both ways
+        let mut shifts: Vec<i32> = (-max_semitones..=max_semitones).collect();
+        shifts.sort_by_key(|shift| (shift.abs(), *shift));
+
+        for semitones in shifts {
+            for compatible in self.transpose(semitones).compatible_keys_with(rules) {
+                if !pitched_keys
+                    .iter()
+                    .any(|pitched| pitched.key == compatible.key)
+                {
+                    pitched_keys.push(PitchedKey {
+                        semitones,
+                        key: compatible.key,
+                        rule: compatible.rule,
+                    });
+                }
+            }
+        }
+
+        pitched_keys
+    }
+
+    // returns the keys a single rule leads to from this key
+    fn apply_mix_rule(&self, rule: MixRule) -> Vec<SongKey> {
+        let (number, minor) = match self.camelot_position() {
+            Some(position) => position,
+            None => return vec![],
+        };
+
+        let key_at = |steps: i32, minor: bool| {
+            SongKey::from_camelot_position(camelot_step(number, steps), minor)
+        };
+
+        match rule {
+            MixRule::SameKey => vec![*self],
+            MixRule::Relative => vec![key_at(0, !minor)],
+            MixRule::Adjacent => vec![key_at(-1, minor), key_at(1, minor)],
+            MixRule::EnergyBoost => vec![key_at(2, minor)],
+            MixRule::PlusSeven => vec![key_at(7, minor)],
+            // minor keys move up to the next major, major keys down to the previous minor
+            MixRule::Diagonal => match minor {
+                true => vec![key_at(1, false)],
+                false => vec![key_at(-1, true)],
+            },
+            // the parallel key: same tonic, other mode (8A <-> 11B)
+            MixRule::MoodSwitch => match minor {
+                true => vec![key_at(3, false)],
+                false => vec![key_at(-3, true)],
+            },
+        }
+    }
+}
+
+// The harmonic mixing moves used to find compatible keys
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum MixRule {
+    // the same key (8A -> 8A)
+    SameKey,
+    // the relative major / minor with the same number (8A -> 8B)
+    Relative,
+    // one step around the wheel in either direction (8A -> 7A, 9A)
+    Adjacent,
+    // two steps clockwise for an "energy boost" (8A -> 10A)
+    EnergyBoost,
+    // seven steps clockwise, which is one semitone up (8A -> 3A)
+    PlusSeven,
+    // one step diagonally across the wheel (8A -> 9B, 8B -> 7A)
+    Diagonal,
+    // switch to the parallel major / minor (8A -> 11B, 8B -> 5A)
+    MoodSwitch,
+}
+
+impl MixRule {
+    // The rules for the classic "same number or one step" compatibility
+    pub const BASIC: &'static [MixRule] = &[MixRule::SameKey, MixRule::Relative, MixRule::Adjacent];
+}
+
+// A key returned by a compatible key lookup, with the rule that produced it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompatibleKey {
+    pub key: SongKey,
+    pub rule: MixRule,
+}
+
+// A key that becomes compatible after pitching a track by `semitones`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PitchedKey {
+    pub semitones: i32,
+    pub key: SongKey,
+    pub rule: MixRule,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+// Shows the musical name of the key, spelled with flats
+impl std::fmt::Display for SongKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_musical_name(Accidental::Flat))
+    }
+}
+
+// Whether black keys are spelled as sharps or flats in musical names
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Accidental {
+    Sharp,
+    Flat,
+}
+
+// The notations a key can be displayed in
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum KeyNotation {
+    // the SongKey names (AMin)
+    Name,
+    // the Camelot wheel (8A)
+    #[default]
+    Camelot,
+    // Open Key (1m)
+    OpenKey,
+    // musical names spelled with flats (A♭ minor)
+    Musical,
+    // musical names spelled with sharps (G♯ minor)
+    MusicalSharps,
 }
 
-#[derive(Serialize, Debug)]
+// Moves a Camelot number around the wheel, wrapping between 12 and 1
+fn camelot_step(number: u32, steps: i32) -> u32 {
+    ((number as i32 - 1 + steps).rem_euclid(12) + 1) as u32
+}
+
+#[derive(Serialize, Debug, Default)]
 pub struct SongMeta {
     pub path: String,
     pub artist: String,
     pub title: String,
     pub key: SongKey,
 
+    // From the tags, or from the file name for the artist and title
+    pub album: String,
+    pub genre: String,
+    pub year: Option<u32>,
+    pub label: String,
+    pub isrc: String,
+
     // The circle-of-fifths key
     pub cof_key: String,
+
+    // How clearly the key stands out from the runner-up (0 when another key matches just
+    // as well, 1 when nothing else matches, missing when no key was found), and the next
+    // best matching keys with their scores
+    pub key_confidence: Option<f32>,
+    pub key_alternatives: Vec<KeyScore>,
+
+    // The sections of a song that modulates, and the circle-of-fifths keys of the song
+    // and all of its sections (so a search can match any of them)
+    pub key_segments: Vec<KeySegment>,
+    pub cof_keys: Vec<String>,
+
+    // The key in the notation chosen for display
+    pub display_key: String,
+
+    // The container format (wav, mp3, mp4, ...) and codec (pcm_s16le, mp3, aac, ...)
+    pub container: String,
+    pub codec: String,
+
+    // The decoded length of the audio and its sample rate, channel count and bit depth
+    // (lossy codecs have no bit depth)
+    pub duration_secs: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub bits_per_sample: Option<u32>,
+
+    // The average bitrate of the audio stream in kbit/s (without tags and cover art), and
+    // the size of the whole file in bytes
+    pub bitrate: Option<u32>,
+    pub file_size: Option<u64>,
+
+    // Free text describing the file (like a BWF description or a comment)
+    pub description: String,
+
+    // Where and when a broadcast WAV was recorded, and its timecode in seconds since midnight
+    pub originator: String,
+    pub origination: String,
+    pub time_reference_secs: Option<f64>,
+
+    // The key and tempo already stored in the file's metadata, if any
+    pub tagged_key: Option<SongKey>,
+    pub tagged_bpm: Option<f64>,
+
+    // The detected tempo and how periodic the onsets are at that tempo (0 to 1)
+    pub bpm: Option<f64>,
+    pub bpm_confidence: Option<f64>,
+
+    // The beats and bars, for exporting to DJ software (an anchor per tempo change makes it
+    // too long for a search record, so it isn't uploaded)
+    #[serde(skip)]
+    pub beat_grid: Option<BeatGrid>,
+
+    // The EBU R128 loudness (in LUFS), loudness range (in LU) and true peak (in dBTP)
+    pub integrated_lufs: Option<f64>,
+    pub loudness_range: Option<f64>,
+    pub true_peak_dbtp: Option<f64>,
+
+    // How energetic the song is, from 1 to 10, and its RMS level over time (up to 600
+    // values, which are kept out of the uploaded record for their size)
+    pub energy_level: Option<u8>,
+    #[serde(skip)]
+    pub energy_curve: Option<EnergyCurve>,
+
+    // The intro, outro, breakdowns and drops, for planning transitions
+    pub cues: Vec<CuePoint>,
+
+    // The position of the song in a chained stream (like an Ogg radio rip) that was split
+    // into one song per chain segment
+    pub chain_segment: Option<u32>,
 }
 
-fn process_mp3_file(path: &str) -> Option<SongMeta> {
+impl SongMeta {
+    // Sets the key along with all of its renderings
+    pub fn set_key(&mut self, key: SongKey, notation: KeyNotation) {
+        self.key = key;
+        self.cof_key = key.to_circle_of_fifths();
+        self.display_key = key.to_notation(notation);
+    }
+
+    // Sets the confidence of the key and its alternatives from the scores of all keys
+    pub fn set_key_scores(&mut self, scores: &[KeyScore]) {
+        // relative keys share their notes, so the raw correlation alone is high for both
+        // even when the audio can't tell them apart
+        self.key_confidence = match scores {
+            [best, runner_up, ..] if best.key == self.key && best.score > 0.0 => {
+                Some(((best.score - runner_up.score.max(0.0)) / best.score).clamp(0.0, 1.0))
+            }
+            [best] if best.key == self.key => Some(1.0),
+            _ => None,
+        };
+        self.key_alternatives = scores
+            .iter()
+            .filter(|score| score.key != self.key)
+            .take(KEY_ALTERNATIVES)
+            .copied()
+            .collect();
+    }
+
+    // The Algolia object ID: the absolute path of the file (and the chain segment), so
+    // indexing a file again replaces its record
+    pub fn object_id(&self) -> String {
+        let path = std::fs::canonicalize(&self.path)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| self.path.clone());
+        match self.chain_segment {
+            Some(segment) => format!("{}#{}", path, segment),
+            None => path,
+        }
+    }
+
+    // Sets the key timeline and collects the keys of its sections
+    pub fn set_key_segments(&mut self, key_segments: Vec<KeySegment>) {
+        self.cof_keys = vec![];
+        let keys = std::iter::once(self.key).chain(key_segments.iter().map(|segment| segment.key));
+        for key in keys {
+            let cof_key = key.to_circle_of_fifths();
+            if key != SongKey::Unknown && !self.cof_keys.contains(&cof_key) {
+                self.cof_keys.push(cof_key);
+            }
+        }
+        self.key_segments = key_segments;
+    }
+}
+
+// The number of runner-up keys stored with each song
+const KEY_ALTERNATIVES: usize = 3;
+
+// What to do when a stream is made of chained segments (like a chained Ogg file)
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChainMode {
+    // keep analysing the segments as a single song
+    #[default]
+    Continue,
+    // analyse each segment as a separate song
+    Split,
+}
+
+// The settings used while indexing files
+#[derive(Debug, Clone)]
+pub struct IndexOptions {
+    // the notation of the display key
+    pub notation: KeyNotation,
+    // how multichannel audio is analysed
+    pub channel_mode: ChannelMode,
+    // the pattern used to get the artist and title of files without tags
+    pub filename_pattern: String,
+    // how chained streams are indexed
+    pub chain_mode: ChainMode,
+    // print the key estimated so far after every this many seconds of audio
+    pub progress_interval: Option<f64>,
+    // the range detected tempos are folded into
+    pub bpm_range: BpmRange,
+}
+
+// The reasons a file cannot be indexed
+#[derive(Debug)]
+pub enum IndexError {
+    // the file cannot be opened or read
+    Io(std::io::Error),
+    // no format reader or decoder for the file
+    UnsupportedFormat(String),
+    // the file has no track with a known codec
+    NoAudioTrack,
+    // the audio track doesn't tell its sample rate
+    MissingSampleRate,
+    // the audio stopped decoding halfway through
+    Decode(String),
+    // there is less audio than needed to find a key
+    TooShort { secs: f64 },
+}
+
+impl std::fmt::Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexError::Io(e) => write!(f, "cannot read file: {}", e),
+            IndexError::UnsupportedFormat(e) => write!(f, "unsupported format: {}", e),
+            IndexError::NoAudioTrack => write!(f, "no supported audio track"),
+            IndexError::MissingSampleRate => write!(f, "the audio track has no sample rate"),
+            IndexError::Decode(e) => write!(f, "decode error: {}", e),
+            IndexError::TooShort { secs } => write!(
+                f,
+                "{:.2}s of audio is too short to detect a key (at least {}s is needed)",
+                secs, MIN_AUDIO_SECS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IndexError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for IndexError {
+    fn from(e: std::io::Error) -> Self {
+        IndexError::Io(e)
+    }
+}
+
+// Guesses the container format from the first bytes of a file
+fn sniff_container(header: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(0, b"RIFF") && at(8, b"WAVE") || at(0, b"RF64") {
+        Some("wav")
+    } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+        Some("aiff")
+    } else if at(0, b"fLaC") {
+        Some("flac")
+    } else if at(0, b"OggS") {
+        Some("ogg")
+    } else if at(4, b"ftyp") {
+        Some("mp4")
+    } else if at(0, b"caff") {
+        Some("caf")
+    } else if at(0, &[0x1a, 0x45, 0xdf, 0xa3]) {
+        Some("mkv")
+    } else if at(0, b"ID3") {
+        Some("mp3")
+    } else if header.len() >= 2 && header[0] == 0xff && header[1] & 0xe0 == 0xe0 {
+        // an MPEG frame sync, where ADTS (AAC) streams have the layer bits cleared
+        match header[1] & 0x06 {
+            0 => Some("aac"),
+            _ => Some("mp3"),
+        }
+    } else {
+        None
+    }
+}
+
+// Reads the container name from the start of the file, falling back to the extension
+fn detect_container(path: &str, extension: Option<&str>) -> String {
+    use std::io::Read;
+
+    let mut header = [0u8; 12];
+    let header_len = std::fs::File::open(path)
+        .and_then(|mut file| file.read(&mut header))
+        .unwrap_or(0);
+
+    match (sniff_container(&header[..header_len]), extension) {
+        (Some(container), _) => String::from(container),
+        (None, Some(extension)) => String::from(extension),
+        (None, None) => String::from("unknown"),
+    }
+}
+
+// Mixes a block of planar samples down to mono. Planar blocks (as the decode loop hands
+// them to the analysers) hold an equal number of frames for each channel, one channel
+// after the other.
+pub fn mono_downmix(samples: &[f32], channel_count: usize) -> impl Iterator<Item = f32> + '_ {
+    let frames = samples.len() / channel_count.max(1);
+    (0..frames).map(move |i| {
+        (0..channel_count)
+            .map(|channel| samples[channel * frames + i])
+            .sum::<f32>()
+            / channel_count as f32
+    })
+}
+
+// The analysers the decoded audio of a song is streamed through
+struct SongAnalysis {
+    sample_rate: u32,
+    channel_audio: ChannelAudio,
+    tempo: TempoAnalyser,
+    loudness: LoudnessMeter,
+
+    // the size of the encoded packets analysed, for the average bitrate
+    packet_bytes: u64,
+}
+
+impl SongAnalysis {
+    fn new(sample_rate: u32, options: &IndexOptions) -> Self {
+        let mut channel_audio = ChannelAudio::new(options.channel_mode, sample_rate);
+        if let Some(interval) = options.progress_interval {
+            let notation = options.notation;
+            channel_audio.on_estimate(
+                interval,
+                Box::new(move |secs, key| print!("\n[{:.0}s] {}", secs, key.to_notation(notation))),
+            );
+        }
+
+        SongAnalysis {
+            sample_rate,
+            channel_audio,
+            tempo: TempoAnalyser::new(sample_rate, options.bpm_range),
+            loudness: LoudnessMeter::new(sample_rate),
+            packet_bytes: 0,
+        }
+    }
+
+    // Adds a block of planar samples to every analyser
+    fn add_planar_samples(
+        &mut self,
+        key_finder: &mut KeyFinder,
+        samples: &[f32],
+        channel_count: usize,
+    ) {
+        self.channel_audio
+            .add_planar_samples(key_finder, samples, channel_count);
+        self.tempo.add_planar_samples(samples, channel_count);
+        self.loudness.add_planar_samples(samples, channel_count);
+    }
+}
+
+// Analyses the audio collected for a song and fills in its key, tempo and loudness
+fn finish_song(
+    key_finder: &mut KeyFinder,
+    mut song_meta: SongMeta,
+    analysis: &mut SongAnalysis,
+    options: &IndexOptions,
+) -> Result<SongMeta, IndexError> {
+    let channel_audio = &mut analysis.channel_audio;
+    let secs = channel_audio.duration_secs();
+    if secs < MIN_AUDIO_SECS {
+        return Err(IndexError::TooShort { secs });
+    }
+    song_meta.duration_secs = Some(secs);
+    song_meta.sample_rate = Some(analysis.sample_rate);
+    song_meta.bitrate = Some((analysis.packet_bytes as f64 * 8.0 / secs / 1000.0).round() as u32);
+    song_meta.set_key(channel_audio.finish(key_finder), options.notation);
+    song_meta.set_key_scores(&channel_audio.key_scores(key_finder));
+    song_meta.set_key_segments(channel_audio.key_timeline(key_finder));
+    if let Some(tempo) = analysis.tempo.finish() {
+        song_meta.bpm = Some(tempo.bpm);
+        song_meta.bpm_confidence = Some(tempo.confidence);
+        song_meta.beat_grid = analysis.tempo.beat_grid(&tempo);
+    }
+    if let Some(loudness) = analysis.loudness.finish() {
+        song_meta.integrated_lufs = Some(loudness.integrated_lufs);
+        song_meta.loudness_range = Some(loudness.loudness_range);
+        song_meta.true_peak_dbtp = Some(loudness.true_peak_dbtp);
+        song_meta.energy_level = Some(loudness.energy_level);
+        song_meta.energy_curve = Some(loudness.energy_curve);
+    }
+    song_meta.cues = cues::find_cues(
+        analysis.loudness.rms_envelope(),
+        song_meta.beat_grid.as_ref(),
+        secs,
+    );
+    tags::apply_filename_pattern(&mut song_meta, &options.filename_pattern);
+    Ok(song_meta)
+}
+
+// Returns one song per file, or one for each segment of a chained stream when
+// `ChainMode::Split` is used
+fn process_audio_file(
+    key_finder: &mut KeyFinder,
+    path: &str,
+    options: &IndexOptions,
+) -> Result<Vec<SongMeta>, IndexError> {
+    use symphonia::core::audio::SampleBuffer;
     use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
     use symphonia::core::errors::Error;
-    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::formats::{FormatOptions, FormatReader, Track};
     use symphonia::core::io::MediaSourceStream;
     use symphonia::core::meta::MetadataOptions;
     use symphonia::core::probe::Hint;
 
-    print!("File: {}\n", path);
+    println!("File: {}", path);
 
     // Open the media source.
-    let src = std::fs::File::open(&path).expect("failed to open media");
+    let src = std::fs::File::open(path)?;
+    let file_size = src.metadata()?.len();
 
     // Create the media source stream.
     let mss = MediaSourceStream::new(Box::new(src), Default::default());
 
-    // Create a probe hint using the file's extension. [Optional]
+    // Create a probe hint using the file's extension. Symphonia still probes the
+    // content, so files with a wrong or missing extension are read as well.
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
     let mut hint = Hint::new();
-    hint.with_extension("mp3");
+    if let Some(extension) = &extension {
+        hint.with_extension(extension);
+    }
 
     // Use the default options for metadata and format readers.
     let meta_opts: MetadataOptions = Default::default();
     let fmt_opts: FormatOptions = Default::default();
 
     // Probe the media source.
-    let probed = symphonia::default::get_probe()
+    let mut probed = symphonia::default::get_probe()
         .format(&hint, mss, &fmt_opts, &meta_opts)
-        .expect("unsupported format");
+        .map_err(|e| match e {
+            Error::IoError(e) => IndexError::Io(e),
+            e => IndexError::UnsupportedFormat(e.to_string()),
+        })?;
+
+    // Tags found before the container (like ID3v2 in front of an MP3 stream)...
+    let mut initial_tags = vec![];
+    if let Some(mut metadata) = probed.metadata.get() {
+        if let Some(rev) = metadata.skip_to_latest() {
+            initial_tags.extend_from_slice(rev.tags());
+        }
+    }
 
     // Get the instantiated format reader.
     let mut format = probed.format;
 
+    // ...and the ones the container itself starts with (Vorbis comments, MP4 atoms, INFO)
+    if let Some(rev) = format.metadata().current() {
+        initial_tags.extend_from_slice(rev.tags());
+    }
+
     // Find the first audio track with a known (decodeable) codec.
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .expect("no supported audio tracks");
+    fn select_track(format: &dyn FormatReader) -> Result<&Track, IndexError> {
+        format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(IndexError::NoAudioTrack)
+    }
+
+    // the short name of the codec, like "mp3" or "flac"
+    fn codec_name(track: &Track) -> &'static str {
+        symphonia::default::get_codecs()
+            .get_codec(track.codec_params.codec)
+            .map(|descriptor| descriptor.short_name)
+            .unwrap_or("unknown")
+    }
+
+    let track = select_track(format.as_ref())?;
+    let codec = codec_name(track);
 
     // find the sample rate
-    let sample_rate = match track.codec_params.sample_rate {
-        Some(rate) => rate,
-        None => {
-            panic!("Cannot find sample rate for track")
-        }
-    };
+    let mut sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or(IndexError::MissingSampleRate)?;
 
-    // create audio data from samplerate
-    let audio_data = unsafe { kfwrapper__init_audio_data(sample_rate) };
-    defer! {
-        unsafe { kfwrapper__destroy_audio_data(audio_data) }
-    }
+    // create the analysers for the sample rate
+    let mut analysis = SongAnalysis::new(sample_rate, options);
 
     print!("Sample rate: {}", sample_rate);
 
@@ -366,10 +1117,13 @@ fn process_mp3_file(path: &str) -> Option<SongMeta> {
     // Create a decoder for the track.
     let mut decoder = symphonia::default::get_codecs()
         .make(&track.codec_params, &dec_opts)
-        .expect("unsupported codec");
+        .map_err(|e| IndexError::UnsupportedFormat(e.to_string()))?;
 
     // Store the track identifier, it will be used to filter packets.
-    let track_id = track.id;
+    let mut track_id = track.id;
+
+    // The songs of the chain segments finished so far
+    let mut songs = vec![];
 
     // The metadata for our song
     let mut song_meta = SongMeta {
@@ -378,23 +1132,117 @@ fn process_mp3_file(path: &str) -> Option<SongMeta> {
         title: String::from(""),
         key: SongKey::Unknown,
         cof_key: String::from("Unknown"),
+        display_key: String::from("Unknown"),
+        container: detect_container(path, extension.as_deref()),
+        codec: String::from(codec),
+        channels: track
+            .codec_params
+            .channels
+            .map(|channels| channels.count() as u32),
+        bits_per_sample: track.codec_params.bits_per_sample,
+        file_size: Some(file_size),
+        ..Default::default()
     };
 
+    // WAV files can carry BWF and RIFF INFO metadata that Symphonia doesn't read
+    if song_meta.container == "wav" {
+        match wavmeta::read_wav_metadata(path) {
+            Ok(wav_meta) => {
+                song_meta.artist = wav_meta.artist;
+                song_meta.title = wav_meta.title;
+                song_meta.description = wav_meta.description;
+                song_meta.originator = wav_meta.originator;
+                song_meta.origination = wav_meta.origination;
+                song_meta.time_reference_secs = wav_meta.time_reference_secs;
+                song_meta.tagged_key = wav_meta.key;
+            }
+            Err(e) => println!("Cannot read WAV metadata: {}", e),
+        }
+    }
+
+    tags::apply_tags(&mut song_meta, &initial_tags);
+
+    // The decoded samples converted to f32, created once we know the size of a packet
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+
     // The decode loop.
     loop {
         // Get the next packet from the media format.
         let packet = match format.next_packet() {
             Ok(packet) => packet,
             Err(Error::ResetRequired) => {
-                // The track list has been changed. Re-examine it and create a new set of decoders,
-                // then restart the decode loop. This is an advanced feature and it is not
-                // unreasonable to consider this "the end." As of v0.5.0, the only usage of this is
-                // for chained OGG physical streams.
-                unimplemented!();
+                // The track list has been changed (as of v0.5.0 this only happens for chained
+                // OGG physical streams). Re-examine it and create a new decoder.
+                let track = select_track(format.as_ref())?;
+                let new_sample_rate = track
+                    .codec_params
+                    .sample_rate
+                    .ok_or(IndexError::MissingSampleRate)?;
+
+                decoder = symphonia::default::get_codecs()
+                    .make(&track.codec_params, &dec_opts)
+                    .map_err(|e| IndexError::UnsupportedFormat(e.to_string()))?;
+                track_id = track.id;
+                sample_buf = None;
+
+                // Audio with a different sample rate can't be analysed together, so that
+                // always starts a new song
+                if options.chain_mode == ChainMode::Split || new_sample_rate != sample_rate {
+                    let segment = song_meta.chain_segment.unwrap_or(0);
+                    // the tags of the new segment arrive with its first packets
+                    let next_song_meta = SongMeta {
+                        path: song_meta.path.clone(),
+                        cof_key: String::from("Unknown"),
+                        display_key: String::from("Unknown"),
+                        container: song_meta.container.clone(),
+                        codec: String::from(codec_name(track)),
+                        channels: track
+                            .codec_params
+                            .channels
+                            .map(|channels| channels.count() as u32),
+                        bits_per_sample: track.codec_params.bits_per_sample,
+                        file_size: song_meta.file_size,
+                        chain_segment: Some(segment + 1),
+                        ..Default::default()
+                    };
+                    song_meta.chain_segment = Some(segment);
+
+                    let finished = std::mem::replace(&mut song_meta, next_song_meta);
+                    match finish_song(key_finder, finished, &mut analysis, options) {
+                        Ok(song) => songs.push(song),
+                        // a jingle between two songs isn't worth failing the file for
+                        Err(IndexError::TooShort { secs }) => {
+                            print!("(skipping {:.2}s chain segment)", secs)
+                        }
+                        Err(e) => return Err(e),
+                    }
+
+                    analysis = SongAnalysis::new(new_sample_rate, options);
+                    sample_rate = new_sample_rate;
+                }
+                continue;
             }
-            Err(_err) => {
-                // A unrecoverable error occured, halt decoding.
-                return Some(song_meta);
+            Err(err) => {
+                // The end of the stream, or an unrecoverable error (most likely a damaged
+                // tail). Either way, analyse the audio decoded up to there.
+                let eof = matches!(
+                    &err,
+                    Error::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof
+                );
+                if !eof {
+                    if songs.is_empty() && analysis.channel_audio.duration_secs() == 0.0 {
+                        return Err(IndexError::Decode(err.to_string()));
+                    }
+                    print!("\nWARNING: decoding stopped early: {}", err);
+                }
+                match finish_song(key_finder, song_meta, &mut analysis, options) {
+                    Ok(song) => songs.push(song),
+                    Err(IndexError::TooShort { secs }) if !songs.is_empty() => {
+                        print!("(skipping {:.2}s chain segment)", secs)
+                    }
+                    Err(e) => return Err(e),
+                }
+                return Ok(songs);
             }
         };
 
@@ -402,14 +1250,10 @@ fn process_mp3_file(path: &str) -> Option<SongMeta> {
         while !format.metadata().is_latest() {
             // Pop the old head of the metadata queue.
             format.metadata().pop();
-            print!("--METADATA--\n");
 
             // Consume the new metadata at the head of the metadata queue.
             if let Some(rev) = format.metadata().current() {
-                // Consume the new metadata at the head of the metadata queue.
-
-                // TODO: get metadata from tags (but they don't seem to work for now)
-                print!("\nTags: {:?}\n", rev.tags());
+                tags::apply_tags(&mut song_meta, rev.tags());
             }
         }
 
@@ -420,55 +1264,39 @@ fn process_mp3_file(path: &str) -> Option<SongMeta> {
 
         // Decode the packet into audio samples.
         match decoder.decode(&packet) {
-            Ok(_decoded) => {
-                // Consume the decoded audio samples (see below).
-                use symphonia::core::audio::{AudioBufferRef, Signal};
-                match _decoded {
-                    AudioBufferRef::F32(buf) => {
-                        let planes = buf.planes();
-                        print!(".");
-                        // check if we have audio channels
-                        if planes.planes().len() == 0 {
-                            print!("No audio channles available");
-                            return None;
-                        }
+            Ok(decoded) => {
+                let spec = *decoded.spec();
+                print!(".");
+                // check if we have audio channels
+                if spec.channels.count() == 0 {
+                    return Err(IndexError::NoAudioTrack);
+                }
 
-                        // use the first channel only (as we are mono)
-                        let plane = planes.planes()[0];
-                        unsafe {
-                            kfwrapper__add_to_samples(
-                                audio_data,
-                                plane.as_ptr(),
-                                plane.len().try_into().unwrap(),
-                            )
-                        };
-
-                        // for plane in planes.planes() {
-                        //     unsafe { kfwrapper__add_to_samples(
-                        //         audio_data,
-                        //         plane.as_ptr(),
-                        //         plane.len().try_into().unwrap()
-                        //     ) }
-                        // // TODO: We have the block of samples here one channel, send to libKeyfinder here
-                        // for &_sample in plane.iter() {
-
-                        //     // Do something with `sample`.
-                        // }
-                        // }
-
-                        // TODO: update the song key from the libkeyfinder instance
-                        // let song_key = SongKey::Unknown;
-                        let int_song_key = unsafe { kfwrapper__key_of_audio(audio_data) };
-                        let song_key = SongKey::from_key_t(int_song_key);
-
-                        song_meta.key = song_key;
-                        song_meta.cof_key = song_key.to_circle_of_fifths();
-                    }
-                    _ => {
-                        // Repeat for the different sample formats.
-                        unimplemented!()
-                    }
+                // some containers only tell the channel layout through the decoded audio
+                song_meta
+                    .channels
+                    .get_or_insert(spec.channels.count() as u32);
+                analysis.packet_bytes += packet.buf().len() as u64;
+
+                // (re)create the sample buffer if this packet does not fit into the current one
+                let capacity = decoded.capacity() * spec.channels.count();
+                if sample_buf
+                    .as_ref()
+                    .is_some_and(|buf| buf.capacity() < capacity)
+                {
+                    sample_buf = None;
                 }
+                let sample_buf = sample_buf
+                    .get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+
+                // convert whatever sample format the decoder produced into planar f32
+                sample_buf.copy_planar_ref(decoded);
+
+                analysis.add_planar_samples(
+                    key_finder,
+                    sample_buf.samples(),
+                    spec.channels.count(),
+                );
             }
             Err(Error::IoError(_)) => {
                 // The packet failed to decode due to an IO error, skip the packet.
@@ -480,7 +1308,7 @@ fn process_mp3_file(path: &str) -> Option<SongMeta> {
             }
             Err(err) => {
                 // An unrecoverable error occured, halt decoding.
-                panic!("Decode error - {}", err);
+                return Err(IndexError::Decode(err.to_string()));
             }
         }
     }
@@ -492,8 +1320,31 @@ fn process_mp3_file(path: &str) -> Option<SongMeta> {
 // The reqwest client type used by the library
 type ClientType = reqwest::blocking::Client;
 
+// The settings used while searching
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    // the mixing rules used to find compatible keys
+    pub rules: Vec<MixRule>,
+    // only return songs whose key was detected with at least this confidence
+    pub min_confidence: Option<f32>,
+    // only return songs with an energy level in this range (1 to 10)
+    pub min_energy: Option<u8>,
+    pub max_energy: Option<u8>,
+    // skip short samples and low quality rips
+    pub min_duration_secs: Option<f64>,
+    pub min_bitrate: Option<u32>,
+    pub min_sample_rate: Option<u32>,
+}
+
 // Searches for a song with a compatible key to the specified one
-pub fn search_algolia_for_song_by_key( app_id: &str, api_key: &str, index_name: &str, key: SongKey, user_query: &str) -> Result<Vec<SongMeta>, String> {
+pub fn search_algolia_for_song_by_key(
+    app_id: &str,
+    api_key: &str,
+    index_name: &str,
+    key: SongKey,
+    options: &SearchOptions,
+    user_query: &str,
+) -> Result<Vec<SongMeta>, String> {
     use url::form_urlencoded::{byte_serialize};
 
     // encode user data for URLs
@@ -501,16 +1352,43 @@ pub fn search_algolia_for_song_by_key( app_id: &str, api_key: &str, index_name:
         byte_serialize(s.as_bytes()).collect()
     }
 
-    fn build_filter_value(keys: &Vec<SongKey>) -> String {
+    fn build_filter_value(keys: &[SongKey], options: &SearchOptions) -> String {
         let keys_strings: Vec<String> = keys
             .iter()
-            .map(|key| format!("cof_key:\"{}\"", key.to_circle_of_fifths()))
+            // songs indexed before key timelines only have `cof_key`
+            .map(|key| {
+                let cof_key = key.to_circle_of_fifths();
+                format!("cof_key:\"{}\" OR cof_keys:\"{}\"", cof_key, cof_key)
+            })
             .collect();
-        keys_strings.join(" OR ")
+
+        let mut filters = vec![format!("({})", keys_strings.join(" OR "))];
+        if let Some(min_confidence) = options.min_confidence {
+            filters.push(format!("key_confidence >= {}", min_confidence));
+        }
+        if let Some(min_energy) = options.min_energy {
+            filters.push(format!("energy_level >= {}", min_energy));
+        }
+        if let Some(max_energy) = options.max_energy {
+            filters.push(format!("energy_level <= {}", max_energy));
+        }
+        if let Some(min_duration_secs) = options.min_duration_secs {
+            filters.push(format!("duration_secs >= {}", min_duration_secs));
+        }
+        if let Some(min_bitrate) = options.min_bitrate {
+            filters.push(format!("bitrate >= {}", min_bitrate));
+        }
+        if let Some(min_sample_rate) = options.min_sample_rate {
+            filters.push(format!("sample_rate >= {}", min_sample_rate));
+        }
+        filters.join(" AND ")
     }
 
-    fn build_query_string(query: &str, keys: &Vec<SongKey>) -> String {
-        let filter_str = format!("filter={}", url_encode(build_filter_value(keys).as_str()));
+    fn build_query_string(query: &str, keys: &[SongKey], options: &SearchOptions) -> String {
+        let filter_str = format!(
+            "filters={}",
+            url_encode(build_filter_value(keys, options).as_str())
+        );
 
         match query {
             "" => filter_str,
@@ -518,6 +1396,16 @@ pub fn search_algolia_for_song_by_key( app_id: &str, api_key: &str, index_name:
         }
     }
 
+    let compatible_keys: Vec<SongKey> = key
+        .compatible_keys_with(&options.rules)
+        .iter()
+        .map(|compatible| compatible.key)
+        .collect();
+    // an empty key group would be a syntax error in the filter
+    if compatible_keys.is_empty() {
+        return Err(format!("no keys are compatible with {}", key));
+    }
+
     let client = ClientType::new();
     let mut have_more = true;
     let mut page = 0;
@@ -529,11 +1417,11 @@ pub fn search_algolia_for_song_by_key( app_id: &str, api_key: &str, index_name:
             "https://{}-dsn.algolia.net/1/indexes/{}?{}&page={}",
             app_id,
             index_name,
-            build_query_string(user_query, &key.compatible_keys()),
-            page.to_string()
+            build_query_string(user_query, &compatible_keys, options),
+            page
         );
 
-        print!("FETCHING ALGOLIA URL:{}\n", url);
+        println!("FETCHING ALGOLIA URL:{}", url);
 
         // send the request
         let res = client
@@ -543,16 +1431,11 @@ pub fn search_algolia_for_song_by_key( app_id: &str, api_key: &str, index_name:
             .send();
 
         match res {
-            Err(e) => return Err(format!("while fetching algolia data: {}", e.to_string())),
+            Err(e) => return Err(format!("while fetching algolia data: {}", e)),
             Ok(response) => match response.bytes() {
-                Err(e) => return Err(format!("while reading Algolia response: {}", e.to_string())),
+                Err(e) => return Err(format!("while reading Algolia response: {}", e)),
                 Ok(bytes) => match decode_search_response(&bytes) {
-                    Err(e) => {
-                        return Err(format!(
-                            "while decoding Algolia response: {}",
-                            e.to_string()
-                        ))
-                    }
+                    Err(e) => return Err(format!("while decoding Algolia response: {}", e)),
                     Ok(search_response) => {
                         song_meta_vec.append(&mut search_response.get_song_meta_vec());
                         have_more = search_response.has_more_pages();
@@ -566,6 +1449,123 @@ pub fn search_algolia_for_song_by_key( app_id: &str, api_key: &str, index_name:
     Ok(song_meta_vec)
 }
 
+// The attributes searches filter on and the order results are ranked in
+fn index_settings() -> serde_json::Value {
+    serde_json::json!({
+        "attributesForFaceting": ["filterOnly(cof_key)", "filterOnly(cof_keys)"],
+        "numericAttributesForFiltering": [
+            "bpm",
+            "key_confidence",
+            "energy_level",
+            "integrated_lufs",
+            "loudness_range",
+            "true_peak_dbtp",
+            "duration_secs",
+            "sample_rate",
+            "bits_per_sample",
+            "bitrate",
+        ],
+        // among equally relevant results the most energetic ones come first
+        "customRanking": ["desc(energy_level)", "desc(integrated_lufs)"],
+    })
+}
+
+// Applies the index settings to an index
+pub fn configure_algolia_index(
+    app_id: &str,
+    api_key: &str,
+    index_name: &str,
+) -> Result<(), String> {
+    let url = format!(
+        "https://{}.algolia.net/1/indexes/{}/settings",
+        app_id, index_name
+    );
+
+    let response = ClientType::new()
+        .put(url)
+        .header("x-algolia-api-key", api_key)
+        .header("x-algolia-application-id", app_id)
+        .json(&index_settings())
+        .send()
+        .map_err(|e| format!("while sending Algolia settings: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "while sending Algolia settings: {} {}",
+            response.status(),
+            response.text().unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+// The number of songs sent to Algolia in one batch request
+const BATCH_SIZE: usize = 1000;
+
+// Collects the songs of an indexing run and uploads them to the Algolia index
+pub struct AlgoliaSender {
+    app_id: String,
+    api_key: String,
+    index_name: String,
+    items: Vec<SongMeta>,
+}
+
+impl AlgoliaSender {
+    pub fn new(app_id: &str, api_key: &str, index_name: &str) -> Self {
+        AlgoliaSender {
+            app_id: String::from(app_id),
+            api_key: String::from(api_key),
+            index_name: String::from(index_name),
+            items: vec![],
+        }
+    }
+
+    pub fn add_item(&mut self, song_meta: SongMeta) {
+        self.items.push(song_meta);
+    }
+
+    // Adds or replaces the songs collected so far in the index, returns how many were sent
+    pub fn send_items(&mut self) -> Result<usize, String> {
+        let url = format!(
+            "https://{}.algolia.net/1/indexes/{}/batch",
+            self.app_id, self.index_name
+        );
+        let client = ClientType::new();
+
+        let mut sent = 0;
+        for batch in self.items.chunks(BATCH_SIZE) {
+            let requests = batch
+                .iter()
+                .map(|song_meta| {
+                    let mut body = serde_json::to_value(song_meta).map_err(|e| e.to_string())?;
+                    body["objectID"] = serde_json::Value::String(song_meta.object_id());
+                    Ok(serde_json::json!({ "action": "updateObject", "body": body }))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+
+            let response = client
+                .post(&url)
+                .header("x-algolia-api-key", &self.api_key)
+                .header("x-algolia-application-id", &self.app_id)
+                .json(&serde_json::json!({ "requests": requests }))
+                .send()
+                .map_err(|e| format!("while sending songs to Algolia: {}", e))?;
+
+            if !response.status().is_success() {
+                return Err(format!(
+                    "while sending songs to Algolia: {} {}",
+                    response.status(),
+                    response.text().unwrap_or_default()
+                ));
+            }
+            sent += batch.len();
+        }
+
+        self.items.clear();
+        Ok(sent)
+    }
+}
+
 // The response for the songMeta type
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -575,6 +1575,30 @@ pub struct SongMetaResponse {
     pub title: String,
     pub key: SongKey,
 
+    // not present for songs indexed before these were added
+    #[serde(default)]
+    pub container: String,
+    #[serde(default)]
+    pub codec: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub album: String,
+    #[serde(default)]
+    pub genre: String,
+    #[serde(default)]
+    pub year: Option<u32>,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default, rename = "key_confidence")]
+    pub key_confidence: Option<f32>,
+    #[serde(default)]
+    pub bpm: Option<f64>,
+    #[serde(default, rename = "energy_level")]
+    pub energy_level: Option<u8>,
+    #[serde(default)]
+    pub cues: Vec<CuePoint>,
+
     #[serde(rename="objectID")]
     pub object_id: String,
 }
@@ -587,6 +1611,19 @@ impl std::convert::From<&SongMetaResponse> for SongMeta {
             title: s.title.clone(),
             key: s.key,
             cof_key: s.key.to_circle_of_fifths(),
+            display_key: s.key.to_notation(KeyNotation::default()),
+            container: s.container.clone(),
+            codec: s.codec.clone(),
+            description: s.description.clone(),
+            album: s.album.clone(),
+            genre: s.genre.clone(),
+            year: s.year,
+            label: s.label.clone(),
+            key_confidence: s.key_confidence,
+            bpm: s.bpm,
+            energy_level: s.energy_level,
+            cues: s.cues.clone(),
+            ..Default::default()
         }
     }
 }
@@ -609,15 +1646,17 @@ pub struct SearchResponse {
     hits: Vec<SongMetaResponse>,
     page: i32,
 
+    #[allow(dead_code)]
     nb_hits: i32,
     nb_pages: i32,
 
+    #[allow(dead_code)]
     hits_per_page: i32,
 }
 
 impl SearchResponse {
     pub fn get_song_meta_vec(&self) -> Vec<SongMeta> {
-        self.hits.iter().map(|r| SongMeta::from(r)).collect()
+        self.hits.iter().map(SongMeta::from).collect()
     }
 
     pub fn has_more_pages(&self) -> bool {
@@ -625,7 +1664,6 @@ impl SearchResponse {
     }
 }
 
-///
 use clap::Parser;
 
 /// Simple program to greet a person
@@ -645,18 +1683,97 @@ struct Args {
     // index to target
     #[arg(short, long)]
     index_name: String,
+
+    // the notation keys are displayed in
+    #[arg(long, value_enum, default_value_t = KeyNotation::default())]
+    notation: KeyNotation,
+}
+
+// How detected keys are written into the tags
+#[derive(Debug, clap::Args)]
+struct TagWriteArgs {
+    // the notation of the key written into the tags
+    #[arg(long, value_enum, default_value_t = KeyNotation::Camelot)]
+    tag_notation: KeyNotation,
+    // only print the tags that would be written
+    #[arg(long)]
+    dry_run: bool,
 }
 
 #[derive(Debug, clap::Subcommand)]
 enum Commands {
     Index {
         file_names: Vec<String>,
+        // how multichannel audio is analysed: mid, vote, left, right or a channel number
+        #[arg(long, default_value = "mid")]
+        channel_mode: ChannelMode,
+        // the key templates the native key detector matches the audio against
+        #[arg(long, value_enum, default_value_t = ToneProfile::default())]
+        tone_profile: ToneProfile,
+        // how to read the artist and title from the names of untagged files, using
+        // {artist}, {title}, {album}, {genre} and {year} placeholders
+        #[arg(long, default_value = tags::DEFAULT_FILENAME_PATTERN)]
+        filename_pattern: String,
+        // whether the segments of chained streams are indexed as one song or one song each
+        #[arg(long, value_enum, default_value_t = ChainMode::default())]
+        chain_mode: ChainMode,
+        // print the key estimated so far after every SECS seconds of audio
+        #[arg(long, value_name = "SECS")]
+        progress: Option<f64>,
+        // the tempo range in BPM, half or double time tempos are folded into it
+        #[arg(long, value_name = "MIN-MAX", default_value = "70-180")]
+        bpm_range: BpmRange,
+        // also write the detected key into the tags of each file
+        #[arg(long)]
+        write_tags: bool,
+        // only print the songs instead of uploading them to the index
+        #[arg(long)]
+        no_upload: bool,
+        #[command(flatten)]
+        tag_write: TagWriteArgs,
+    },
+    // detect the key of each file and write it into the file's tags
+    TagWrite {
+        file_names: Vec<String>,
+        // how multichannel audio is analysed: mid, vote, left, right or a channel number
+        #[arg(long, default_value = "mid")]
+        channel_mode: ChannelMode,
+        // the key templates the native key detector matches the audio against
+        #[arg(long, value_enum, default_value_t = ToneProfile::default())]
+        tone_profile: ToneProfile,
+        #[command(flatten)]
+        tag_write: TagWriteArgs,
     },
     Search {
         query: String,
-        #[arg(short, long, value_enum)]
+        // the key in any common notation (AMin, 8A, 1m, A minor, Am)
+        #[arg(short, long, value_parser = parse_search_key)]
         key: SongKey,
+        // the mixing rules used to find compatible keys
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = MixRule::BASIC.to_vec())]
+        rules: Vec<MixRule>,
+        // only suggest songs whose key was detected with at least this confidence (0 to 1)
+        #[arg(long)]
+        min_confidence: Option<f32>,
+        // only suggest songs with at least this energy level (1 to 10)
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
+        min_energy: Option<u8>,
+        // only suggest songs with at most this energy level (1 to 10)
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
+        max_energy: Option<u8>,
+        // only suggest songs that are at least this long
+        #[arg(long, value_name = "SECS")]
+        min_duration: Option<f64>,
+        // only suggest songs with at least this average bitrate in kbit/s
+        #[arg(long, value_name = "KBPS")]
+        min_bitrate: Option<u32>,
+        // only suggest songs with at least this sample rate in Hz
+        #[arg(long, value_name = "HZ")]
+        min_sample_rate: Option<u32>,
     },
+    // set up the index for searching: the key attributes as facets, the numeric
+    // attributes as filters and the energy level for ranking
+    Configure,
 }
 
 const RESPONSE_EXAMPLE: &str = r#"
@@ -734,38 +1851,113 @@ const RESPONSE_EXAMPLE: &str = r#"
 fn decode_search_response(data: &[u8]) -> Result<SearchResponse, String> {
     match serde_json::from_slice(data) {
         Ok(response) => Ok(response),
-        Err(e) => Err(format!("while decoding response: {}", e.to_string())),
+        Err(e) => Err(format!("while decoding response: {}", e)),
     }
 }
 
-fn print_search_results(results: &Vec<SongMeta>) {
-    print!("Artist\tTitle\tKey\tPath\n");
+fn print_search_results(results: &Vec<SongMeta>, notation: KeyNotation) {
+    println!("Artist\tTitle\tKey\tBPM\tEnergy\tCues\tPath");
     for result in results {
-        print!(
-            "{}\t{}\t{}\t{}\n",
+        let cues: Vec<String> = result.cues.iter().map(|cue| cue.to_string()).collect();
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
             result.artist,
             result.title,
-            result.key.to_circle_of_fifths(),
+            result.key.to_notation(notation),
+            result
+                .bpm
+                .map(|bpm| format!("{:.1}", bpm))
+                .unwrap_or_default(),
+            result
+                .energy_level
+                .map(|energy| energy.to_string())
+                .unwrap_or_default(),
+            cues.join(", "),
             result.path
         )
     }
 }
 
-fn run_search(app_id: &str, api_key: &str, index_name: &str, key: SongKey, query_string: &str) {
-    match search_algolia_for_song_by_key(app_id, api_key, index_name, key, query_string) {
+// Prints how many files were processed and why the others failed
+fn print_index_summary(total: usize, failures: &[(String, IndexError)]) {
+    println!("\nProcessed {} of {} files", total - failures.len(), total);
+    if !failures.is_empty() {
+        println!("Failed:");
+        for (path, error) in failures {
+            println!("\t{}: {}", path, error);
+        }
+    }
+}
+
+// Writes the detected key of a song into its file and prints what changed
+fn write_key_tags(song_meta: &SongMeta, tag_write: &TagWriteArgs) {
+    if song_meta.key == SongKey::Unknown {
+        println!(
+            "No key detected for {}, tags left as they are",
+            song_meta.path
+        );
+        return;
+    }
+    if let Some(segment) = song_meta.chain_segment {
+        println!(
+            "{} is split into chain segments, not writing the key of segment {}",
+            song_meta.path, segment
+        );
+        return;
+    }
+
+    let key = song_meta.key.to_notation(tag_write.tag_notation);
+    match tags::write_key(&song_meta.path, &key, tag_write.dry_run) {
+        Err(e) => println!("ERROR: cannot write tags of {}: {}", song_meta.path, e),
+        Ok(changes) => {
+            for change in changes {
+                println!(
+                    "{} {}: {} -> {}{}",
+                    song_meta.path,
+                    change.field,
+                    change.previous.as_deref().unwrap_or("(none)"),
+                    change.key,
+                    if tag_write.dry_run { " (dry run)" } else { "" }
+                );
+            }
+        }
+    }
+}
+
+// Parses the key to search with, which has to be a real key to have compatible ones
+fn parse_search_key(s: &str) -> Result<SongKey, String> {
+    match s.parse() {
+        Ok(SongKey::Unknown) => Err(String::from(
+            "cannot search for keys compatible with an unknown key",
+        )),
+        Ok(key) => Ok(key),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+fn run_search(
+    app_id: &str,
+    api_key: &str,
+    index_name: &str,
+    key: SongKey,
+    options: &SearchOptions,
+    query_string: &str,
+    notation: KeyNotation,
+) {
+    match search_algolia_for_song_by_key(app_id, api_key, index_name, key, options, query_string) {
         Err(e) => {
             print!("ERROR: {}", e);
         }
         Ok(results) => {
             print!("---- RESULTS ----");
-            print_search_results(&results);
+            print_search_results(&results, notation);
         }
     }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    print!("ARGS: {:?}\n", args);
+    println!("ARGS: {:?}", args);
 
     match decode_search_response(RESPONSE_EXAMPLE.as_bytes()) {
         Err(e) => {
@@ -777,34 +1969,626 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 results,
                 results.has_more_pages()
             );
-            print_search_results(&results.get_song_meta_vec());
+            print_search_results(&results.get_song_meta_vec(), args.notation);
         }
     }
     // panic!("Stop");
 
     match args.command {
-        Commands::Index { file_names } => {
-            /*
-            // Create the sender from the credentials
-            let mut sender = AlgoliaSender::new(args.app_id, args.api_key, args.index_name);
+        Commands::Index {
+            file_names,
+            channel_mode,
+            tone_profile,
+            filename_pattern,
+            chain_mode,
+            progress,
+            bpm_range,
+            write_tags,
+            no_upload,
+            tag_write,
+        } => {
+            // the key finder retains useful resources between files
+            let mut key_finder = KeyFinder::new();
+            key_finder.set_tone_profile(tone_profile);
+            let options = IndexOptions {
+                notation: args.notation,
+                channel_mode,
+                filename_pattern,
+                chain_mode,
+                progress_interval: progress,
+                bpm_range,
+            };
 
-            for filename in file_names {
-                let song_meta = process_mp3_file(&filename);
-                print!("Song meta: {:?}\n ", song_meta);
-
-                // add the metadata to the send objects list
-                sender.add_item(song_meta);
+            // Create the sender from the credentials
+            let mut sender = AlgoliaSender::new(&args.app_id, &args.api_key, &args.index_name);
+
+            // one bad file shouldn't stop the batch, so failures are listed at the end
+            let mut failures = vec![];
+            for filename in &file_names {
+                match process_audio_file(&mut key_finder, filename, &options) {
+                    Ok(songs) => {
+                        for song_meta in songs {
+                            println!("Song meta: {:?}", song_meta);
+                            if write_tags {
+                                write_key_tags(&song_meta, &tag_write);
+                            }
+
+                            // add the metadata to the send objects list
+                            if !no_upload {
+                                sender.add_item(song_meta);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("\nERROR: {}: {}", filename, e);
+                        failures.push((filename.clone(), e));
+                    }
+                }
             }
+            print_index_summary(file_names.len(), &failures);
 
             // send the data
-            sender.send_items();
-            */
+            if !no_upload {
+                let sent = sender.send_items()?;
+                println!("Sent {} songs to {}", sent, args.index_name);
+            }
+            Ok(())
+        }
+        Commands::TagWrite {
+            file_names,
+            channel_mode,
+            tone_profile,
+            tag_write,
+        } => {
+            let mut key_finder = KeyFinder::new();
+            key_finder.set_tone_profile(tone_profile);
+            let options = IndexOptions {
+                notation: args.notation,
+                channel_mode,
+                filename_pattern: String::from(tags::DEFAULT_FILENAME_PATTERN),
+                // the file has a single set of tags, so it gets a single key
+                chain_mode: ChainMode::Continue,
+                progress_interval: None,
+                bpm_range: BpmRange::default(),
+            };
+
+            let mut failures = vec![];
+            for filename in &file_names {
+                match process_audio_file(&mut key_finder, filename, &options) {
+                    Ok(songs) => songs
+                        .iter()
+                        .for_each(|song_meta| write_key_tags(song_meta, &tag_write)),
+                    Err(e) => {
+                        println!("\nERROR: {}: {}", filename, e);
+                        failures.push((filename.clone(), e));
+                    }
+                }
+            }
+            print_index_summary(file_names.len(), &failures);
             Ok(())
         },
-        Commands::Search { query, key } => {
-            run_search(&args.app_id, &args.api_key, &args.index_name, key, &query);
+        Commands::Search {
+            query,
+            key,
+            rules,
+            min_confidence,
+            min_energy,
+            max_energy,
+            min_duration,
+            min_bitrate,
+            min_sample_rate,
+        } => {
+            let options = SearchOptions {
+                rules,
+                min_confidence,
+                min_energy,
+                max_energy,
+                min_duration_secs: min_duration,
+                min_bitrate,
+                min_sample_rate,
+            };
+            run_search(
+                &args.app_id,
+                &args.api_key,
+                &args.index_name,
+                key,
+                &options,
+                &query,
+                args.notation,
+            );
+            Ok(())
+        }
+        Commands::Configure => {
+            configure_algolia_index(&args.app_id, &args.api_key, &args.index_name)?;
+            println!("Index {} configured", args.index_name);
             Ok(())
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camelot_position_matches_circle_of_fifths_notation() {
+        for key in SongKey::ALL {
+            let (number, minor) = key.camelot_position().unwrap();
+            let letter = if minor { "A" } else { "B" };
+            assert_eq!(format!("{}{}", number, letter), key.to_circle_of_fifths());
+            assert_eq!(SongKey::from_camelot_position(number, minor), key);
+        }
+    }
+
+    #[test]
+    fn compatible_keys_of_a_minor() {
+        assert_eq!(
+            SongKey::AMin.compatible_keys(),
+            vec![SongKey::AMin, SongKey::CMaj, SongKey::DMin, SongKey::EMin]
+        );
+    }
+
+    #[test]
+    fn compatible_keys_wrap_around_the_wheel() {
+        // 12B and 1B are neighbours
+        assert_eq!(
+            SongKey::EMaj.compatible_keys(),
+            vec![SongKey::EMaj, SongKey::DfMin, SongKey::AMaj, SongKey::BMaj]
+        );
+        assert_eq!(
+            SongKey::BMaj.compatible_keys(),
+            vec![SongKey::BMaj, SongKey::AfMin, SongKey::EMaj, SongKey::GfMaj]
+        );
+    }
+
+    #[test]
+    fn compatible_keys_for_all_keys() {
+        for key in SongKey::ALL {
+            let (number, minor) = key.camelot_position().unwrap();
+            let compatible = key.compatible_keys();
+
+            let mut positions: Vec<(u32, bool)> = compatible
+                .iter()
+                .map(|k| k.camelot_position().unwrap())
+                .collect();
+            positions.sort();
+
+            let mut expected = vec![
+                (number, minor),
+                (number, !minor),
+                (camelot_step(number, -1), minor),
+                (camelot_step(number, 1), minor),
+            ];
+            expected.sort();
+
+            assert_eq!(positions, expected, "compatible keys of {:?}", key);
+        }
+    }
+
+    #[test]
+    fn compatible_keys_are_symmetric() {
+        for key in SongKey::ALL {
+            for other in key.compatible_keys() {
+                assert!(
+                    other.compatible_keys().contains(&key),
+                    "{:?} is compatible with {:?} but not the other way around",
+                    key,
+                    other
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mix_rules_from_a_minor() {
+        let keys_for = |rule| -> Vec<SongKey> {
+            SongKey::AMin
+                .compatible_keys_with(&[rule])
+                .iter()
+                .map(|compatible| compatible.key)
+                .collect()
+        };
+
+        assert_eq!(keys_for(MixRule::SameKey), vec![SongKey::AMin]);
+        assert_eq!(keys_for(MixRule::Relative), vec![SongKey::CMaj]);
+        assert_eq!(
+            keys_for(MixRule::Adjacent),
+            vec![SongKey::DMin, SongKey::EMin]
+        );
+        assert_eq!(keys_for(MixRule::EnergyBoost), vec![SongKey::BMin]);
+        assert_eq!(keys_for(MixRule::PlusSeven), vec![SongKey::BfMin]);
+        assert_eq!(keys_for(MixRule::Diagonal), vec![SongKey::GMaj]);
+        assert_eq!(keys_for(MixRule::MoodSwitch), vec![SongKey::AMaj]);
+    }
+
+    #[test]
+    fn compatible_keys_are_tagged_with_the_first_matching_rule() {
+        let compatible = SongKey::CMaj.compatible_keys_with(&[
+            MixRule::MoodSwitch,
+            MixRule::SameKey,
+            MixRule::Relative,
+            MixRule::Diagonal,
+        ]);
+
+        assert_eq!(
+            compatible,
+            vec![
+                CompatibleKey {
+                    key: SongKey::CMin,
+                    rule: MixRule::MoodSwitch
+                },
+                CompatibleKey {
+                    key: SongKey::CMaj,
+                    rule: MixRule::SameKey
+                },
+                CompatibleKey {
+                    key: SongKey::AMin,
+                    rule: MixRule::Relative
+                },
+                CompatibleKey {
+                    key: SongKey::DMin,
+                    rule: MixRule::Diagonal
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_enum_names() {
+        assert_eq!("AMin".parse(), Ok(SongKey::AMin));
+        assert_eq!("bfmaj".parse(), Ok(SongKey::BfMaj));
+        assert_eq!("Unknown".parse(), Ok(SongKey::Unknown));
+
+        for key in SongKey::ALL {
+            assert_eq!(format!("{:?}", key).parse(), Ok(key));
+        }
+    }
+
+    #[test]
+    fn parse_camelot_and_open_key() {
+        assert_eq!("8A".parse(), Ok(SongKey::AMin));
+        assert_eq!("8b".parse(), Ok(SongKey::CMaj));
+        assert_eq!("12B".parse(), Ok(SongKey::EMaj));
+        assert_eq!("1m".parse(), Ok(SongKey::AMin));
+        assert_eq!("1d".parse(), Ok(SongKey::CMaj));
+        assert_eq!("6m".parse(), Ok(SongKey::AfMin));
+        assert_eq!("12m".parse(), Ok(SongKey::DMin));
+        assert_eq!("12d".parse(), Ok(SongKey::FMaj));
+
+        for key in SongKey::ALL {
+            assert_eq!(key.to_circle_of_fifths().parse(), Ok(key));
+        }
+    }
+
+    #[test]
+    fn parse_musical_notation() {
+        assert_eq!("A minor".parse(), Ok(SongKey::AMin));
+        assert_eq!("Am".parse(), Ok(SongKey::AMin));
+        assert_eq!("C#m".parse(), Ok(SongKey::DfMin));
+        assert_eq!("Bbmaj".parse(), Ok(SongKey::BfMaj));
+        assert_eq!("A#".parse(), Ok(SongKey::BfMaj));
+        assert_eq!("F♯ minor".parse(), Ok(SongKey::GfMin));
+        assert_eq!("E flat minor".parse(), Ok(SongKey::EfMin));
+        assert_eq!("Cb".parse(), Ok(SongKey::BMaj));
+        assert_eq!("E#".parse(), Ok(SongKey::FMaj));
+        assert_eq!("g".parse(), Ok(SongKey::GMaj));
+        assert_eq!("D Major".parse(), Ok(SongKey::DMaj));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("".parse::<SongKey>().is_err());
+        assert!("13A".parse::<SongKey>().is_err());
+        assert!("0B".parse::<SongKey>().is_err());
+        assert!("8C".parse::<SongKey>().is_err());
+        assert!("H minor".parse::<SongKey>().is_err());
+        assert!("A dorian".parse::<SongKey>().is_err());
+
+        let error = "H minor".parse::<SongKey>().unwrap_err();
+        assert!(error.to_string().contains("H minor"));
+    }
+
+    #[test]
+    fn open_key_notation() {
+        assert_eq!(SongKey::CMaj.to_open_key(), "1d");
+        assert_eq!(SongKey::AMin.to_open_key(), "1m");
+        assert_eq!(SongKey::FMaj.to_open_key(), "12d");
+        assert_eq!(SongKey::AfMin.to_open_key(), "6m");
+        assert_eq!(SongKey::Unknown.to_open_key(), "Unknown");
+
+        for key in SongKey::ALL {
+            assert_eq!(key.to_open_key().parse(), Ok(key));
+        }
+    }
+
+    #[test]
+    fn musical_names() {
+        assert_eq!(SongKey::AfMin.to_musical_name(Accidental::Flat), "A♭ minor");
+        assert_eq!(
+            SongKey::AfMin.to_musical_name(Accidental::Sharp),
+            "G♯ minor"
+        );
+        assert_eq!(SongKey::CMaj.to_musical_name(Accidental::Sharp), "C major");
+        assert_eq!(SongKey::EfMaj.to_string(), "E♭ major");
+        assert_eq!(SongKey::Unknown.to_string(), "Unknown");
+
+        for key in SongKey::ALL {
+            assert_eq!(key.to_musical_name(Accidental::Flat).parse(), Ok(key));
+            assert_eq!(key.to_musical_name(Accidental::Sharp).parse(), Ok(key));
+        }
+    }
+
+    #[test]
+    fn sniff_containers() {
+        let cases: &[(&[u8], Option<&str>)] = &[
+            (b"RIFF\x24\x08\x00\x00WAVEfmt ", Some("wav")),
+            (b"RF64\xff\xff\xff\xffWAVEds64", Some("wav")),
+            (b"FORM\x00\x00\x00\x00AIFFCOMM", Some("aiff")),
+            (b"FORM\x00\x00\x00\x00AIFCFVER", Some("aiff")),
+            (b"fLaC\x00\x00\x00\x22", Some("flac")),
+            (b"OggS\x00\x02", Some("ogg")),
+            (b"\x00\x00\x00\x20ftypM4A ", Some("mp4")),
+            (b"caff\x00\x01\x00\x00", Some("caf")),
+            (b"\x1a\x45\xdf\xa3\x01\x00", Some("mkv")),
+            (b"ID3\x04\x00\x00", Some("mp3")),
+            (b"\xff\xfb\x90\x64", Some("mp3")),
+            (b"\xff\xf1\x50\x80", Some("aac")),
+            // a RIFF file that isn't a WAV, a FORM that isn't AIFF and truncated headers
+            (b"RIFF\x24\x08\x00\x00AVI LIST", None),
+            (b"FORM\x00\x00\x00\x00ILBM", None),
+            (b"RIFF\x24\x08", None),
+            (b"\xff", None),
+            (b"", None),
+            (b"just some text", None),
+        ];
+
+        for (header, expected) in cases {
+            assert_eq!(sniff_container(header), *expected, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn uploaded_songs_have_the_indexed_attributes() {
+        let record = serde_json::to_value(SongMeta::default()).unwrap();
+        let settings = index_settings();
+        let attributes = settings["numericAttributesForFiltering"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|attribute| attribute.as_str().unwrap())
+            .chain(["cof_key", "cof_keys", "energy_level", "integrated_lufs"]);
+        for attribute in attributes {
+            assert!(
+                record.get(attribute).is_some(),
+                "{} is not uploaded",
+                attribute
+            );
+        }
+
+        // the curves and grids that are too long for a record stay local
+        let record = serde_json::to_value(SongMeta {
+            beat_grid: Some(BeatGrid {
+                anchors: vec![],
+                beat_count: 0,
+                bar_phase: 0,
+            }),
+            energy_curve: Some(EnergyCurve {
+                interval_secs: 1.0,
+                rms_db: vec![-20.0; 600],
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(record.get("beat_grid"), None);
+        assert_eq!(record.get("energy_curve"), None);
+        assert!(record.get("cues").is_some());
+    }
+
+    #[test]
+    fn search_key_must_be_known() {
+        assert_eq!(parse_search_key("8A"), Ok(SongKey::AMin));
+        assert!(parse_search_key("Unknown").is_err());
+        assert!(parse_search_key("13A").is_err());
+    }
+
+    #[test]
+    fn key_confidence_is_the_margin_to_the_runner_up() {
+        let score = |key, score| KeyScore { key, score };
+        let mut song_meta = SongMeta {
+            key: SongKey::AMin,
+            ..Default::default()
+        };
+
+        // relative keys matching almost equally well are ambiguous
+        song_meta.set_key_scores(&[score(SongKey::AMin, 0.80), score(SongKey::CMaj, 0.79)]);
+        assert!(song_meta.key_confidence.unwrap() < 0.05);
+
+        song_meta.set_key_scores(&[
+            score(SongKey::AMin, 0.80),
+            score(SongKey::AMaj, 0.40),
+            score(SongKey::CMaj, 0.35),
+            score(SongKey::DMin, 0.30),
+            score(SongKey::EMin, 0.20),
+        ]);
+        assert!((song_meta.key_confidence.unwrap() - 0.5).abs() < 1e-6);
+        assert_eq!(song_meta.key_alternatives.len(), KEY_ALTERNATIVES);
+        assert_eq!(song_meta.key_alternatives[0].key, SongKey::AMaj);
+
+        // scores for another key than the detected one say nothing about it
+        song_meta.set_key_scores(&[score(SongKey::CMaj, 0.80), score(SongKey::AMin, 0.79)]);
+        assert_eq!(song_meta.key_confidence, None);
+        song_meta.set_key_scores(&[]);
+        assert_eq!(song_meta.key_confidence, None);
+    }
+
+    #[test]
+    fn transpose_keys() {
+        assert_eq!(SongKey::AMin.transpose(1), SongKey::BfMin);
+        assert_eq!(SongKey::AMin.transpose(-2), SongKey::GMin);
+        assert_eq!(SongKey::BMaj.transpose(1), SongKey::CMaj);
+        assert_eq!(SongKey::CMaj.transpose(-13), SongKey::BMaj);
+        assert_eq!(SongKey::Unknown.transpose(3), SongKey::Unknown);
+
+        for key in SongKey::ALL {
+            assert_eq!(key.transpose(12), key);
+            assert_eq!(key.transpose(5).transpose(-5), key);
+            // a semitone up is seven steps clockwise on the wheel
+            let (number, minor) = key.camelot_position().unwrap();
+            assert_eq!(
+                key.transpose(1),
+                SongKey::from_camelot_position(camelot_step(number, 7), minor)
+            );
+        }
+    }
+
+    #[test]
+    fn relative_and_parallel_keys() {
+        assert_eq!(SongKey::AMin.relative(), SongKey::CMaj);
+        assert_eq!(SongKey::CMaj.relative(), SongKey::AMin);
+        assert_eq!(SongKey::AMin.parallel(), SongKey::AMaj);
+        assert_eq!(SongKey::EfMaj.parallel(), SongKey::EfMin);
+
+        for key in SongKey::ALL {
+            assert_eq!(key.relative().relative(), key);
+            assert_eq!(key.parallel().parallel(), key);
+            assert_eq!(
+                key.relative().camelot_position().unwrap().0,
+                key.camelot_position().unwrap().0
+            );
+            assert_eq!(key.parallel().tonic_pitch_class(), key.tonic_pitch_class());
+            assert_ne!(key.parallel().mode(), key.mode());
+        }
+    }
+
+    #[test]
+    fn tonic_and_mode() {
+        assert_eq!(SongKey::AMin.tonic_pitch_class(), Some(9));
+        assert_eq!(SongKey::AMin.mode(), Some(Mode::Minor));
+        assert_eq!(SongKey::DfMaj.tonic_pitch_class(), Some(1));
+        assert_eq!(SongKey::DfMaj.mode(), Some(Mode::Major));
+        assert_eq!(SongKey::Unknown.tonic_pitch_class(), None);
+        assert_eq!(SongKey::Unknown.mode(), None);
+    }
+
+    #[test]
+    fn compatible_keys_when_pitched() {
+        let pitched = SongKey::AMin.compatible_keys_when_pitched(1, &[MixRule::SameKey]);
+        assert_eq!(
+            pitched,
+            vec![
+                PitchedKey {
+                    semitones: 0,
+                    key: SongKey::AMin,
+                    rule: MixRule::SameKey
+                },
+                PitchedKey {
+                    semitones: -1,
+                    key: SongKey::AfMin,
+                    rule: MixRule::SameKey
+                },
+                PitchedKey {
+                    semitones: 1,
+                    key: SongKey::BfMin,
+                    rule: MixRule::SameKey
+                },
+            ]
+        );
+
+        // without pitching this is the plain lookup
+        let unpitched: Vec<SongKey> = SongKey::GMaj
+            .compatible_keys_when_pitched(0, MixRule::BASIC)
+            .iter()
+            .map(|pitched| pitched.key)
+            .collect();
+        assert_eq!(unpitched, SongKey::GMaj.compatible_keys());
+
+        // pitching by up to 6 semitones reaches every key of the same mode
+        let all_minor = SongKey::AMin.compatible_keys_when_pitched(6, &[MixRule::SameKey]);
+        assert_eq!(all_minor.len(), 12);
+    }
+
+    #[test]
+    fn all_keys_are_distinct_and_in_declaration_order() {
+        for (i, key) in SongKey::ALL.iter().enumerate() {
+            assert_eq!(*key as usize, i);
+            assert_eq!(SongKey::from_pitch_class(i % 12, i >= 12), *key);
+        }
+    }
+
+    #[test]
+    fn key_t_matches_libkeyfinder_constants() {
+        assert_eq!(SongKey::AMaj.to_key_t(), 0);
+        assert_eq!(SongKey::AMin.to_key_t(), 1);
+        assert_eq!(SongKey::CMaj.to_key_t(), 6);
+        assert_eq!(SongKey::AfMin.to_key_t(), 23);
+        assert_eq!(SongKey::Unknown.to_key_t(), 24);
+
+        assert_eq!(SongKey::try_from(24), Ok(SongKey::Unknown));
+        assert_eq!(SongKey::try_from(25), Err(InvalidKeyT(25)));
+        assert_eq!(SongKey::try_from(-1), Err(InvalidKeyT(-1)));
+        assert_eq!(SongKey::from_key_t(100), SongKey::Unknown);
+    }
+
+    #[test]
+    fn key_t_round_trips() {
+        for i in -10..40 {
+            match SongKey::try_from(i) {
+                Ok(key) => assert_eq!(key.to_key_t(), i),
+                Err(_) => assert!(!(0..=24).contains(&i)),
+            }
+        }
+
+        for key in SongKey::ALL {
+            assert_eq!(SongKey::try_from(key.to_key_t()), Ok(key));
+            // key_t alternates major and minor from A
+            let (pitch_class, minor) = key.tonic().unwrap();
+            assert_eq!(
+                key.to_key_t(),
+                ((pitch_class as i32 + 3) % 12) * 2 + minor as i32
+            );
+        }
+    }
+
+    #[test]
+    fn all_notations_round_trip() {
+        for &notation in <KeyNotation as clap::ValueEnum>::value_variants() {
+            let rendered: Vec<String> = SongKey::ALL
+                .iter()
+                .map(|key| key.to_notation(notation))
+                .collect();
+
+            // every key has its own rendering
+            let mut distinct = rendered.clone();
+            distinct.sort();
+            distinct.dedup();
+            assert_eq!(
+                distinct.len(),
+                24,
+                "{:?} renders two keys the same",
+                notation
+            );
+
+            for (key, text) in SongKey::ALL.iter().zip(rendered) {
+                assert_eq!(
+                    text.parse(),
+                    Ok(*key),
+                    "{:?} of {:?} is {}",
+                    notation,
+                    key,
+                    text
+                );
+            }
+        }
+
+        for key in SongKey::ALL {
+            assert_eq!(key.to_string().parse(), Ok(key));
+        }
+    }
+
+    #[test]
+    fn unknown_key_has_no_compatible_keys() {
+        assert_eq!(SongKey::Unknown.compatible_keys(), vec![]);
+        assert_eq!(SongKey::Unknown.camelot_position(), None);
+    }
+}
@@ -0,0 +1,295 @@
+// Song metadata from tags and file names
+// --------------------------------------
+//
+// Symphonia maps most ID3v2, Vorbis comment, MP4 and RIFF INFO tags to `StandardTagKey`s.
+// The initial key has no standard key, so it is matched on the raw tag names instead.
+// Files without tags fall back to parsing their name with a pattern like
+// `{artist} - {title}`.
+//
+// Symphonia can't write tags, so the detected key is written back with `lofty` (and into
+// the iXML chunk of WAV files by `wavmeta`).
+
+use lofty::config::WriteOptions;
+use lofty::file::{FileType, TaggedFileExt};
+use lofty::tag::{ItemKey, TagExt};
+use symphonia::core::meta::{StandardTagKey, Tag, Value};
+
+use crate::{wavmeta, SongMeta};
+
+// The default pattern for file names without tags
+pub const DEFAULT_FILENAME_PATTERN: &str = "{artist} - {title}";
+
+// Copies the tags into the song metadata. Fields that already have a value are left alone,
+// so the first source (BWF chunks, then tags before the audio, then tags in the stream)
+// wins.
+pub fn apply_tags(song_meta: &mut SongMeta, tags: &[Tag]) {
+    for tag in tags {
+        let value = match tag_text(&tag.value) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        match tag.std_key {
+            Some(StandardTagKey::Artist) => fill(&mut song_meta.artist, value),
+            Some(StandardTagKey::TrackTitle) => fill(&mut song_meta.title, value),
+            Some(StandardTagKey::Album) => fill(&mut song_meta.album, value),
+            Some(StandardTagKey::Genre) => fill(&mut song_meta.genre, value),
+            Some(StandardTagKey::Label) => fill(&mut song_meta.label, value),
+            Some(StandardTagKey::IdentIsrc) => fill(&mut song_meta.isrc, value),
+            Some(StandardTagKey::Comment) | Some(StandardTagKey::Description) => {
+                fill(&mut song_meta.description, value)
+            }
+            Some(StandardTagKey::Date) | Some(StandardTagKey::OriginalDate) => {
+                song_meta.year = song_meta.year.or_else(|| parse_year(&value))
+            }
+            Some(StandardTagKey::Bpm) => {
+                song_meta.tagged_bpm = song_meta
+                    .tagged_bpm
+                    .or_else(|| value.parse().ok().filter(|bpm: &f64| *bpm > 0.0))
+            }
+            _ if is_key_tag(&tag.key) => {
+                song_meta.tagged_key = song_meta.tagged_key.or_else(|| value.parse().ok())
+            }
+            _ => {}
+        }
+    }
+}
+
+// Fills whatever the tags left empty from the file name
+pub fn apply_filename_pattern(song_meta: &mut SongMeta, pattern: &str) {
+    let stem = match std::path::Path::new(&song_meta.path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+    {
+        Some(stem) => stem.to_string(),
+        None => return,
+    };
+
+    match match_filename_pattern(pattern, &stem) {
+        Some(fields) => {
+            for (name, value) in fields {
+                match name.as_str() {
+                    "artist" => fill(&mut song_meta.artist, value),
+                    "title" => fill(&mut song_meta.title, value),
+                    "album" => fill(&mut song_meta.album, value),
+                    "genre" => fill(&mut song_meta.genre, value),
+                    "year" => song_meta.year = song_meta.year.or_else(|| parse_year(&value)),
+                    _ => {}
+                }
+            }
+        }
+        // a name that doesn't fit the pattern is still a better title than nothing
+        None => fill(&mut song_meta.title, stem),
+    }
+}
+
+// Matches a file name against a pattern of `{field}` placeholders and literal text.
+// Every placeholder but the last takes the shortest text up to the next literal, the
+// last one takes the rest. Returns the (field, value) pairs of a full match.
+pub fn match_filename_pattern(pattern: &str, name: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = vec![];
+    let mut pattern = pattern;
+    let mut name = name;
+
+    while !pattern.is_empty() {
+        match pattern.strip_prefix('{') {
+            Some(rest) => {
+                let close = rest.find('}')?;
+                let field = &rest[..close];
+                pattern = &rest[close + 1..];
+
+                // the value runs until the next literal text (or the end of the name)
+                let literal_end = pattern.find('{').unwrap_or(pattern.len());
+                let literal = &pattern[..literal_end];
+                let value_end = if literal.is_empty() {
+                    name.len()
+                } else {
+                    name.find(literal)?
+                };
+
+                let value = name[..value_end].trim();
+                if value.is_empty() {
+                    return None;
+                }
+                fields.push((field.to_string(), value.to_string()));
+                name = &name[value_end..];
+            }
+            None => {
+                let literal_end = pattern.find('{').unwrap_or(pattern.len());
+                name = name.strip_prefix(&pattern[..literal_end])?;
+                pattern = &pattern[literal_end..];
+            }
+        }
+    }
+
+    if name.is_empty() {
+        Some(fields)
+    } else {
+        None
+    }
+}
+
+// A key tag that was (or would be) written to a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyTagChange {
+    // The tag and field, like "ID3v2 TKEY"
+    pub field: String,
+    pub previous: Option<String>,
+    pub key: String,
+}
+
+// Writes the key into the file's own tag format (ID3v2 TKEY for MP3, WAV and AIFF,
+// Vorbis INITIALKEY for FLAC and Ogg, the iTunes initialkey atom for MP4) and into the
+// iXML chunk of WAV files. Only the tags are rewritten, the audio data is left as it is.
+// In a dry run nothing is written, but the changes are still returned.
+pub fn write_key(path: &str, key: &str, dry_run: bool) -> Result<Vec<KeyTagChange>, String> {
+    let mut tagged_file = lofty::read_from_path(path).map_err(|e| e.to_string())?;
+    let tag_type = tagged_file.primary_tag_type();
+    let file_type = tagged_file.file_type();
+
+    if tagged_file.tag(tag_type).is_none() {
+        tagged_file.insert_tag(lofty::tag::Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .tag_mut(tag_type)
+        .ok_or_else(|| format!("cannot add a {:?} tag", tag_type))?;
+
+    let mut changes = vec![KeyTagChange {
+        field: format!(
+            "{:?} {}",
+            tag_type,
+            ItemKey::InitialKey
+                .map_key(tag_type)
+                .unwrap_or("initial key")
+        ),
+        previous: tag.get_string(ItemKey::InitialKey).map(String::from),
+        key: String::from(key),
+    }];
+
+    if !dry_run {
+        if !tag.insert_text(ItemKey::InitialKey, String::from(key)) {
+            return Err(format!("{:?} tags cannot store a key", tag_type));
+        }
+        tag.save_to_path(path, WriteOptions::default())
+            .map_err(|e| e.to_string())?;
+    }
+
+    if file_type == FileType::Wav {
+        changes.push(KeyTagChange {
+            field: String::from("iXML INITIALKEY"),
+            previous: wavmeta::write_ixml_key(path, key, dry_run)?,
+            key: String::from(key),
+        });
+    }
+
+    Ok(changes)
+}
+
+// The raw tag names of the initial key in ID3v2 (TKEY), Vorbis comments (INITIALKEY, KEY)
+// and MP4 (the iTunes `----:com.apple.iTunes:initialkey` freeform atom)
+fn is_key_tag(key: &str) -> bool {
+    let key = key.to_lowercase();
+    key == "tkey" || key == "initialkey" || key == "key" || key.ends_with(":initialkey")
+}
+
+// The trimmed text of a tag value, if there is any
+fn tag_text(value: &Value) -> Option<String> {
+    let text = match value {
+        // RIFF INFO strings keep their NUL terminator
+        Value::String(s) => s
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::SignedInt(i) => i.to_string(),
+        Value::UnsignedInt(u) => u.to_string(),
+        Value::Binary(_) | Value::Boolean(_) | Value::Flag => return None,
+    };
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+// Sets a text field unless it already has a value
+fn fill(field: &mut String, value: String) {
+    if field.is_empty() {
+        *field = value;
+    }
+}
+
+// The year of dates like "2019", "2019-04-01" or "2019-04-01T12:00:00"
+fn parse_year(date: &str) -> Option<u32> {
+    date.get(..4)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the (field, value) pairs a pattern should match
+    type Fields = &'static [(&'static str, &'static str)];
+
+    #[test]
+    fn filename_patterns() {
+        let cases: &[(&str, &str, Option<Fields>)] = &[
+            (
+                "{artist} - {title}",
+                "Daft Punk - One More Time",
+                Some(&[("artist", "Daft Punk"), ("title", "One More Time")]),
+            ),
+            // only the last placeholder takes a repeated separator
+            (
+                "{artist} - {title}",
+                "Artist - Title - Extended Mix",
+                Some(&[("artist", "Artist"), ("title", "Title - Extended Mix")]),
+            ),
+            (
+                "[{label}] {artist} - {title} ({year})",
+                "[Kompakt] Artist - Title (2004)",
+                Some(&[
+                    ("label", "Kompakt"),
+                    ("artist", "Artist"),
+                    ("title", "Title"),
+                    ("year", "2004"),
+                ]),
+            ),
+            // values are trimmed
+            (
+                "{artist}-{title}",
+                "Artist  -  Title",
+                Some(&[("artist", "Artist"), ("title", "Title")]),
+            ),
+            // no separator, a missing literal prefix or trailing text
+            ("{artist} - {title}", "Just a name", None),
+            ("[{label}] {artist} - {title}", "Artist - Title", None),
+            ("{artist} - {title}.", "Artist - Title", None),
+            // empty fields
+            ("{artist} - {title}", " - Title", None),
+            ("{artist} - {title}", "Artist - ", None),
+            ("{artist}{title}", "Artist Title", None),
+            // an unclosed placeholder
+            ("{artist - {title", "Artist - Title", None),
+            ("{artist} - {title", "Artist - Title", None),
+            // a pattern without placeholders only matches itself
+            ("intro", "intro", Some(&[])),
+            ("", "", Some(&[])),
+        ];
+
+        for (pattern, name, expected) in cases {
+            let expected = expected.map(|fields| {
+                fields
+                    .iter()
+                    .map(|(field, value)| (field.to_string(), value.to_string()))
+                    .collect::<Vec<_>>()
+            });
+            assert_eq!(
+                match_filename_pattern(pattern, name),
+                expected,
+                "{:?} on {:?}",
+                pattern,
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,918 @@
+// Tempo detection
+// ---------------
+//
+// The decoded audio is mixed down to mono and turned into an onset strength envelope:
+// the summed increase of the log-magnitude spectrum between two FFT frames (spectral
+// flux), which peaks wherever a note or a drum hit starts. Like the key analysis this is
+// streaming, only the samples of one unfinished FFT frame are kept besides the envelope.
+//
+// Once the stream ends, the periodicity of the envelope is measured by autocorrelation.
+// Every beat period in the configured BPM range is scored together with its multiples
+// (a comb, so a real beat beats its own off-beats), weighted towards the middle of the
+// range, and the winner is refined to a fraction of a frame. Audio without a beat still
+// has onsets, but no period stands out of the others, so a tempo has to score well above
+// the mean of the range. A plain pulse correlates just as well at every multiple of its
+// period, so the comb can't tell a tempo from its half time: a period is halved when the
+// onsets halfway between its beats are nearly as strong as the beats themselves.
+//
+// The beats themselves are then tracked by dynamic programming (Ellis, "Beat Tracking by
+// Dynamic Programming", 2007): the best chain of onsets that are about one beat period
+// apart. The beats are stored as a beat grid of constant tempo anchors, so a track with a
+// steady tempo needs a single anchor however long it is.
+
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+// The length of the FFT frame in seconds (rounded to a power of two frames)
+const FRAME_SECS: f64 = 0.046;
+
+// The number of envelope values per FFT frame
+const HOPS_PER_FRAME: usize = 8;
+
+// How quickly the preference for the middle of the BPM range falls off (in octaves)
+const PREFERENCE_OCTAVES: f64 = 1.0;
+
+// A period is halved when half of it scores at least this share of its comb score
+const DOUBLE_TIME_RATIO: f64 = 0.9;
+
+// The weights of the multiples of a beat period in its score
+const COMB_WEIGHTS: [f64; 4] = [1.0, 0.5, 0.25, 0.25];
+
+// Kick drums and bass notes are below this, they decide between the beats and the
+// off-beats (where hi-hats often make stronger onsets) and find the downbeats
+const BASS_HZ: f64 = 150.0;
+
+// The number of beats the envelope has to cover to measure its tempo
+const MIN_BEATS: f64 = 4.0;
+
+// How strongly the beat tracker sticks to the tempo, against following the onsets
+const TIGHTNESS: f64 = 100.0;
+
+// Beats further than this off a constant tempo line start a new beat grid anchor, if
+// `ANCHOR_RUN` beats in a row are off it (fewer are taken as misplaced beats)
+const ANCHOR_TOLERANCE_SECS: f64 = 0.02;
+const ANCHOR_RUN: usize = 4;
+
+// The BPM of an anchor is kept within this fraction of the tempo: the beats are tracked at
+// the tempo, anchors far off it come from misplaced beats rather than a tempo change
+const ANCHOR_BPM_TOLERANCE: f64 = 0.06;
+
+// Below this tempo confidence the beats are too unsteady to lay a grid on
+const MIN_GRID_CONFIDENCE: f64 = 0.3;
+
+// Bars are assumed to have 4 beats
+pub const BEATS_PER_BAR: u32 = 4;
+
+// The comb score of a tempo has to be at least this many times the mean comb score over
+// the BPM range to be a tempo at all
+const MIN_PERIODICITY: f64 = 4.0;
+
+// The BPM range a tempo is reported in, half or double time tempos outside it are folded
+// into it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BpmRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Default for BpmRange {
+    fn default() -> Self {
+        BpmRange {
+            min: 70.0,
+            max: 180.0,
+        }
+    }
+}
+
+impl std::str::FromStr for BpmRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid BPM range \"{}\": expected MIN-MAX, like 70-180", s);
+
+        let (min, max) = s.split_once('-').ok_or_else(invalid)?;
+        let range = BpmRange {
+            min: min.trim().parse().map_err(|_| invalid())?,
+            max: max.trim().parse().map_err(|_| invalid())?,
+        };
+        // the range must hold at least an octave, so every tempo can be folded into it
+        if range.min <= 0.0 || range.max < range.min * 2.0 {
+            return Err(format!(
+                "invalid BPM range \"{}\": the maximum must be at least twice the minimum",
+                s
+            ));
+        }
+        Ok(range)
+    }
+}
+
+// A detected tempo
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tempo {
+    pub bpm: f64,
+    // How periodic the onsets are at this tempo (0 to 1)
+    pub confidence: f64,
+}
+
+// Streams decoded audio into an onset envelope and finds its tempo
+pub struct TempoAnalyser {
+    frame_rate: u32,
+    range: BpmRange,
+
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+
+    // mono samples not yet covered by a complete FFT frame
+    pending: Vec<f32>,
+
+    // the log-magnitude spectrum of the last frame, and its (linear) magnitude below
+    // `BASS_HZ`
+    previous_spectrum: Vec<f32>,
+    previous_bass: Vec<f32>,
+
+    // the onset strength of every hop, over the whole spectrum and below `BASS_HZ` (where
+    // it isn't log compressed, to keep the accents of the downbeats)
+    envelope: Vec<f32>,
+    bass_envelope: Vec<f32>,
+}
+
+impl TempoAnalyser {
+    pub fn new(frame_rate: u32, range: BpmRange) -> Self {
+        let ideal = (frame_rate as f64 * FRAME_SECS).max(64.0);
+        let frame_size = 1 << ideal.log2().round() as u32;
+
+        TempoAnalyser {
+            frame_rate,
+            range,
+            fft: FftPlanner::new().plan_fft_forward(frame_size),
+            window: hann_window(frame_size),
+            pending: vec![],
+            // the stream starts after silence, so a note on the first sample is an onset
+            previous_spectrum: vec![0.0; frame_size / 2],
+            previous_bass: vec![
+                0.0;
+                (BASS_HZ * frame_size as f64 / frame_rate as f64).ceil() as usize
+            ],
+            envelope: vec![],
+            bass_envelope: vec![],
+        }
+    }
+
+    // The number of frames between two envelope values
+    pub fn hop_size(&self) -> usize {
+        self.window.len() / HOPS_PER_FRAME
+    }
+
+    // The length of one envelope value in seconds
+    pub fn hop_secs(&self) -> f64 {
+        self.hop_size() as f64 / self.frame_rate as f64
+    }
+
+    // The stream position of an envelope value: the centre of its FFT frame
+    pub fn envelope_secs(&self, index: usize) -> f64 {
+        (index * self.hop_size() + self.window.len() / 2) as f64 / self.frame_rate as f64
+    }
+
+    // Mixes a block of planar samples down to mono and extends the onset envelope by every
+    // FFT frame the block completes
+    pub fn add_planar_samples(&mut self, samples: &[f32], channel_count: usize) {
+        if channel_count == 0 {
+            return;
+        }
+        self.pending
+            .extend(crate::mono_downmix(samples, channel_count));
+
+        let frame_size = self.window.len();
+        let hop_size = self.hop_size();
+        let mut start = 0;
+        while start + frame_size <= self.pending.len() {
+            let (onset, bass_onset) = self.onset_of_frame(start);
+            self.envelope.push(onset);
+            self.bass_envelope.push(bass_onset);
+            start += hop_size;
+        }
+        self.pending.drain(..start);
+    }
+
+    // Returns the spectral flux between the frame starting at `start` and the last one, over
+    // the whole spectrum and below `BASS_HZ`
+    fn onset_of_frame(&mut self, start: usize) -> (f32, f32) {
+        let mut buffer: Vec<Complex<f32>> = self
+            .window
+            .iter()
+            .zip(self.pending[start..].iter())
+            .map(|(weight, sample)| Complex::new(sample * weight, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+
+        // log compression keeps quiet hi-hats from drowning in loud bass notes
+        let spectrum: Vec<f32> = buffer[..buffer.len() / 2]
+            .iter()
+            .map(|bin| (1.0 + 100.0 * bin.norm()).ln())
+            .collect();
+
+        let bass: Vec<f32> = buffer[..self.previous_bass.len()]
+            .iter()
+            .map(|bin| bin.norm())
+            .collect();
+
+        let onset = flux(&spectrum, &self.previous_spectrum);
+        let bass_onset = flux(&bass, &self.previous_bass);
+        self.previous_spectrum = spectrum;
+        self.previous_bass = bass;
+        (onset, bass_onset)
+    }
+
+    // Finds the tempo of the whole stream, or nothing if the audio is too short or its
+    // onsets don't repeat at any one period
+    pub fn finish(&self) -> Option<Tempo> {
+        let hop_secs = self.hop_secs();
+        let lag_of_bpm = |bpm: f64| 60.0 / (bpm * hop_secs);
+
+        let min_lag = lag_of_bpm(self.range.max).floor().max(1.0) as usize;
+        let max_lag = lag_of_bpm(self.range.min).ceil() as usize;
+        if (self.envelope.len() as f64) < max_lag as f64 * MIN_BEATS {
+            return None;
+        }
+
+        let mean = self.envelope.iter().sum::<f32>() / self.envelope.len() as f32;
+        let envelope: Vec<f32> = self.envelope.iter().map(|value| value - mean).collect();
+
+        // the autocorrelation for every lag the comb looks at, normalised by the energy
+        let max_comb_lag = (max_lag + 1) * COMB_WEIGHTS.len();
+        let energy = autocorrelation(&envelope, 0);
+        let variation = (energy / envelope.len() as f64).sqrt() / mean as f64;
+        if energy <= 0.0 {
+            return None;
+        }
+        let correlations: Vec<f64> = (0..=max_comb_lag)
+            .map(|lag| autocorrelation(&envelope, lag) / energy)
+            .collect();
+
+        // the peaks of the multiples drift off the multiples of a whole lag by up to half a
+        // hop per multiple, so the comb takes the peak within that drift
+        let comb_score = |lag: usize| -> f64 {
+            COMB_WEIGHTS
+                .iter()
+                .enumerate()
+                .map(|(i, weight)| {
+                    let multiple = i + 1;
+                    let (centre, drift) = (lag * multiple, multiple / 2);
+                    weight
+                        * correlations[centre - drift..=centre + drift]
+                            .iter()
+                            .fold(0.0f64, |a, b| a.max(*b))
+                })
+                .sum::<f64>()
+                / COMB_WEIGHTS.iter().sum::<f64>()
+        };
+        let preferred_bpm = (self.range.min * self.range.max).sqrt();
+        let preference = |lag: usize| -> f64 {
+            let octaves = (60.0 / (lag as f64 * hop_secs) / preferred_bpm).log2();
+            (-0.5 * (octaves / PREFERENCE_OCTAVES).powi(2)).exp()
+        };
+
+        let mut best_lag = (min_lag..=max_lag).max_by(|&a, &b| {
+            (comb_score(a) * preference(a)).total_cmp(&(comb_score(b) * preference(b)))
+        })?;
+
+        // beats stand out at their own period, while the flux of sustained sounds (the
+        // partials of a chord beating against each other) flickers at every period alike
+        let mean_comb_score =
+            (min_lag..=max_lag).map(comb_score).sum::<f64>() / (max_lag - min_lag + 1) as f64;
+        if comb_score(best_lag) <= MIN_PERIODICITY * mean_comb_score {
+            return None;
+        }
+
+        // the comb of a fractional period, taking the peak around each multiple (a whole
+        // lag can be far enough off for the multiples to miss their peaks)
+        let peak_comb_score = |period: f64| -> f64 {
+            COMB_WEIGHTS
+                .iter()
+                .enumerate()
+                .map(|(i, weight)| {
+                    let lag = (period * (i + 1) as f64).round() as usize;
+                    weight
+                        * correlations[lag - 1..=lag + 1]
+                            .iter()
+                            .fold(0.0f64, |a, b| a.max(*b))
+                })
+                .sum::<f64>()
+                / COMB_WEIGHTS.iter().sum::<f64>()
+        };
+        let half_period = best_lag as f64 / 2.0;
+        if half_period >= min_lag as f64
+            && peak_comb_score(half_period) >= DOUBLE_TIME_RATIO * peak_comb_score(best_lag as f64)
+        {
+            best_lag = (min_lag..=max_lag)
+                .filter(|lag| (*lag as f64 - half_period).abs() <= 1.0)
+                .max_by(|&a, &b| comb_score(a).total_cmp(&comb_score(b)))?;
+        }
+
+        let period = refine_period(&correlations, best_lag);
+        let bpm = fold_into_range(60.0 / (period * hop_secs), self.range);
+
+        Some(Tempo {
+            bpm: (bpm * 100.0).round() / 100.0,
+            // weak onsets make for a weak beat, however periodic they are
+            confidence: (correlations[best_lag] * variation.min(1.0)).clamp(0.0, 1.0),
+        })
+    }
+}
+
+// A point of the beat grid from which the beats follow at a constant tempo, until the
+// next anchor
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct BeatAnchor {
+    // The index of the beat at the anchor
+    pub beat: u32,
+    pub secs: f64,
+    pub bpm: f64,
+}
+
+// The beats of a track
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BeatGrid {
+    // The first anchor is at the first beat, there are more only if the tempo changes
+    pub anchors: Vec<BeatAnchor>,
+    pub beat_count: u32,
+    // The index of the first downbeat, the first beat of a bar (0 to `BEATS_PER_BAR` - 1)
+    pub bar_phase: u32,
+}
+
+impl BeatGrid {
+    // The offset of the first beat in seconds
+    pub fn first_beat_secs(&self) -> f64 {
+        self.anchors
+            .first()
+            .map(|anchor| anchor.secs)
+            .unwrap_or(0.0)
+    }
+
+    // The position of a beat in seconds
+    pub fn beat_secs(&self, beat: u32) -> f64 {
+        match self.anchors.iter().rev().find(|anchor| anchor.beat <= beat) {
+            Some(anchor) => anchor.secs + (beat - anchor.beat) as f64 * 60.0 / anchor.bpm,
+            None => self.first_beat_secs(),
+        }
+    }
+
+    // The positions of all beats in seconds
+    pub fn beats_secs(&self) -> Vec<f64> {
+        (0..self.beat_count)
+            .map(|beat| self.beat_secs(beat))
+            .collect()
+    }
+
+    // Whether a beat is the first beat of a bar
+    pub fn is_downbeat(&self, beat: u32) -> bool {
+        beat % BEATS_PER_BAR == self.bar_phase
+    }
+}
+
+impl TempoAnalyser {
+    // Tracks the beats of the whole stream at the tempo found by `finish()`, or nothing if
+    // the tempo is too weak to follow
+    pub fn beat_grid(&self, tempo: &Tempo) -> Option<BeatGrid> {
+        if tempo.confidence < MIN_GRID_CONFIDENCE {
+            return None;
+        }
+        let period = 60.0 / (tempo.bpm * self.hop_secs());
+        let mut beats = track_beats(&self.envelope, period);
+
+        // the tracker can't tell the beats from the off-beats, but the kick drum can
+        let bass_strength = |hops: &mut dyn Iterator<Item = usize>| -> f64 {
+            let strengths: Vec<f64> = hops
+                .map(|hop| {
+                    self.bass_envelope
+                        [hop.saturating_sub(2)..(hop + 3).min(self.bass_envelope.len())]
+                        .iter()
+                        .fold(0.0f32, |max, value| max.max(*value)) as f64
+                })
+                .collect();
+            strengths.iter().sum::<f64>() / strengths.len().max(1) as f64
+        };
+        let off_beats: Vec<usize> = beats
+            .windows(2)
+            .map(|pair| (pair[0] + pair[1]) / 2)
+            .collect();
+        if bass_strength(&mut off_beats.iter().copied()) > bass_strength(&mut beats.iter().copied())
+        {
+            beats = off_beats;
+        }
+
+        if (beats.len() as f64) < MIN_BEATS {
+            return None;
+        }
+        let beat_secs: Vec<f64> = beats.iter().map(|&hop| self.envelope_secs(hop)).collect();
+
+        // the downbeats are taken to be the beats with the strongest bass
+        let phase_strength = |phase: usize| -> f64 {
+            bass_strength(
+                &mut beats
+                    .iter()
+                    .copied()
+                    .skip(phase)
+                    .step_by(BEATS_PER_BAR as usize),
+            )
+        };
+        let bar_phase = (0..BEATS_PER_BAR as usize)
+            .max_by(|&a, &b| phase_strength(a).total_cmp(&phase_strength(b)))
+            .unwrap_or(0);
+
+        Some(BeatGrid {
+            anchors: fit_anchors(&beat_secs, tempo.bpm),
+            beat_count: beats.len() as u32,
+            bar_phase: bar_phase as u32,
+        })
+    }
+}
+
+// Finds the beats (as envelope indexes) by dynamic programming: every onset gets the
+// score of the best chain of beats ending on it, where gaps that differ from the beat
+// period are penalised. The chain ending near the end of the stream wins.
+fn track_beats(envelope: &[f32], period: f64) -> Vec<usize> {
+    let length = envelope.len();
+    if length == 0 || period < 1.0 {
+        return vec![];
+    }
+
+    // onsets normalised by their standard deviation and smoothed a little, so a beat
+    // doesn't have to land on the exact hop of its onset
+    let mean = envelope.iter().sum::<f32>() as f64 / length as f64;
+    let deviation = (envelope
+        .iter()
+        .map(|value| (*value as f64 - mean).powi(2))
+        .sum::<f64>()
+        / length as f64)
+        .sqrt();
+    if deviation == 0.0 {
+        return vec![];
+    }
+    let sigma = (period / 32.0).max(1.0);
+    let radius = (sigma * 3.0).ceil() as usize;
+    let local: Vec<f64> = (0..length)
+        .map(|i| {
+            (i.saturating_sub(radius)..(i + radius + 1).min(length))
+                .map(|j| {
+                    let distance = (j as f64 - i as f64) / sigma;
+                    envelope[j] as f64 / deviation * (-0.5 * distance * distance).exp()
+                })
+                .sum()
+        })
+        .collect();
+
+    let min_gap = ((period / 2.0).round() as usize).max(1);
+    let max_gap = (period * 2.0).round() as usize;
+    let mut score = vec![0.0; length];
+    let mut previous: Vec<Option<usize>> = vec![None; length];
+    for i in 0..length {
+        let best = (min_gap..=max_gap.min(i))
+            .map(|gap| {
+                let penalty = TIGHTNESS * (gap as f64 / period).ln().powi(2);
+                (i - gap, score[i - gap] - penalty)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        score[i] = local[i];
+        if let Some((beat, beat_score)) = best {
+            score[i] += beat_score;
+            previous[i] = Some(beat);
+        }
+    }
+
+    // the last beat is the best scoring one within the last two beat periods
+    let last_window = length.saturating_sub((period * 2.0) as usize);
+    let mut beat = (last_window..length).max_by(|&a, &b| score[a].total_cmp(&score[b]));
+    let mut beats = vec![];
+    while let Some(b) = beat {
+        beats.push(b);
+        beat = previous[b];
+    }
+    beats.reverse();
+
+    // the tracker keeps counting through silence, drop the beats before the music starts
+    // and after it ends
+    let threshold = 0.5 * beats.iter().map(|&b| local[b]).sum::<f64>() / beats.len() as f64;
+    let first = beats.iter().position(|&b| local[b] >= threshold);
+    let last = beats.iter().rposition(|&b| local[b] >= threshold);
+    match (first, last) {
+        (Some(first), Some(last)) => beats[first..=last].to_vec(),
+        _ => vec![],
+    }
+}
+
+// Turns beat positions into constant tempo anchors: beats are fitted to a line (beat
+// index against time) until a run of them is off it, which starts a new anchor. Single
+// beats off the line are left out of the fit, and the BPM of an anchor is kept within
+// `ANCHOR_BPM_TOLERANCE` of `bpm`.
+fn fit_anchors(beat_secs: &[f64], bpm: f64) -> Vec<BeatAnchor> {
+    let (min_period, max_period) = (
+        60.0 / (bpm * (1.0 + ANCHOR_BPM_TOLERANCE)),
+        60.0 / (bpm * (1.0 - ANCHOR_BPM_TOLERANCE)),
+    );
+    let mut anchors = vec![];
+    let mut start = 0;
+
+    while start + 1 < beat_secs.len() {
+        // the line starts from the median beat interval and offset of the first beats, so
+        // a misplaced one among them doesn't tilt it
+        let first = &beat_secs[start..(start + ANCHOR_RUN + 1).min(beat_secs.len())];
+        let period = median(first.windows(2).map(|pair| pair[1] - pair[0]).collect());
+        let seed = (
+            median(
+                first
+                    .iter()
+                    .enumerate()
+                    .map(|(i, secs)| secs - period * i as f64)
+                    .collect(),
+            ),
+            period,
+        );
+
+        let mut line = LineFit::default();
+        let mut end = start;
+        while end < beat_secs.len() {
+            let (offset, slope) = if line.count >= 2.0 { line.line() } else { seed };
+            let off_line = |beat: usize| {
+                (offset + slope * (beat - start) as f64 - beat_secs[beat]).abs()
+                    > ANCHOR_TOLERANCE_SECS
+            };
+            if off_line(end) {
+                if end >= start + ANCHOR_RUN
+                    && end + ANCHOR_RUN <= beat_secs.len()
+                    && (end..end + ANCHOR_RUN).all(off_line)
+                {
+                    break;
+                }
+            } else {
+                line.add((end - start) as f64, beat_secs[end]);
+            }
+            end += 1;
+        }
+
+        let (mut offset, mut slope) = if line.count >= 2.0 { line.line() } else { seed };
+        if !(min_period..=max_period).contains(&slope) {
+            // the best line through the beats at the nearest period in the tolerance
+            slope = slope.clamp(min_period, max_period);
+            if line.count > 0.0 {
+                offset = line.offset_at_slope(slope);
+            }
+        }
+        anchors.push(BeatAnchor {
+            beat: start as u32,
+            secs: (offset * 1000.0).round() / 1000.0,
+            bpm: (60.0 / slope * 1000.0).round() / 1000.0,
+        });
+        start = end;
+    }
+
+    anchors
+}
+
+// The median of some values, 0 if there are none
+fn median(mut values: Vec<f64>) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f64::total_cmp);
+    // the two middle values are the same one for an odd count
+    (values[(values.len() - 1) / 2] + values[values.len() / 2]) / 2.0
+}
+
+// A least squares line fitted to points added one at a time
+#[derive(Default)]
+struct LineFit {
+    count: f64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xy: f64,
+    sum_xx: f64,
+}
+
+impl LineFit {
+    fn add(&mut self, x: f64, y: f64) {
+        self.count += 1.0;
+        self.sum_x += x;
+        self.sum_y += y;
+        self.sum_xy += x * y;
+        self.sum_xx += x * x;
+    }
+
+    // Returns the (offset, slope) of the line, it needs two points with different x
+    fn line(&self) -> (f64, f64) {
+        let slope = (self.count * self.sum_xy - self.sum_x * self.sum_y)
+            / (self.count * self.sum_xx - self.sum_x * self.sum_x);
+        (self.offset_at_slope(slope), slope)
+    }
+
+    // Returns the offset of the line with a given slope
+    fn offset_at_slope(&self, slope: f64) -> f64 {
+        (self.sum_y - slope * self.sum_x) / self.count
+    }
+}
+
+// The summed increase of the magnitudes from one spectrum to the next
+fn flux(spectrum: &[f32], previous: &[f32]) -> f32 {
+    spectrum
+        .iter()
+        .zip(previous.iter())
+        .map(|(current, previous)| (current - previous).max(0.0))
+        .sum()
+}
+
+// Moves a tempo into the range by doubling or halving it
+fn fold_into_range(mut bpm: f64, range: BpmRange) -> f64 {
+    while bpm < range.min {
+        bpm *= 2.0;
+    }
+    while bpm > range.max {
+        bpm /= 2.0;
+    }
+    bpm
+}
+
+// Estimates the beat period (in hops) more precisely than a whole lag: the peaks at
+// multiples of the lag are located to a fraction of a hop and averaged, longer
+// multiples pinning the period down more precisely
+fn refine_period(correlations: &[f64], lag: usize) -> f64 {
+    let mut periods = 0.0;
+    let mut weights = 0.0;
+
+    for multiple in 1..=COMB_WEIGHTS.len() {
+        let expected = lag * multiple;
+        // the peak of the multiple may have drifted by a hop for each multiple
+        let search = expected.saturating_sub(multiple).max(1)
+            ..=(expected + multiple).min(correlations.len() - 2);
+        let peak = match search.max_by(|&a, &b| correlations[a].total_cmp(&correlations[b])) {
+            Some(peak) => peak,
+            None => continue,
+        };
+
+        // the vertex of the parabola through the peak and its neighbours
+        let (left, centre, right) = (
+            correlations[peak - 1],
+            correlations[peak],
+            correlations[peak + 1],
+        );
+        let curvature = left - 2.0 * centre + right;
+        let offset = if curvature < 0.0 {
+            (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
+
+        // each multiple estimates the period as its position / multiple, weighted by the
+        // multiple
+        periods += peak as f64 + offset;
+        weights += multiple as f64;
+    }
+
+    if weights == 0.0 {
+        lag as f64
+    } else {
+        periods / weights
+    }
+}
+
+// The (unnormalised) autocorrelation of a signal at a lag
+fn autocorrelation(signal: &[f32], lag: usize) -> f64 {
+    if lag >= signal.len() {
+        return 0.0;
+    }
+    signal[..signal.len() - lag]
+        .iter()
+        .zip(signal[lag..].iter())
+        .map(|(a, b)| (a * b) as f64)
+        .sum()
+}
+
+fn hann_window(size: usize) -> Vec<f32> {
+    use std::f32::consts::PI;
+
+    (0..size)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_RATE: u32 = 22050;
+
+    // A kick-like pulse: a decaying pitch sweep on every beat
+    fn pulse(bpm: f64, secs: f64) -> Vec<f32> {
+        let period = (60.0 / bpm * FRAME_RATE as f64) as usize;
+        (0..(secs * FRAME_RATE as f64) as usize)
+            .map(|i| {
+                let t = (i % period) as f32 / FRAME_RATE as f32;
+                let frequency = 50.0 + 100.0 * (-t * 30.0).exp();
+                (2.0 * std::f32::consts::PI * frequency * t).sin() * (-t * 20.0).exp()
+            })
+            .collect()
+    }
+
+    // The pulse with hi-hats (short noise bursts) on every sixteenth note, accented on the
+    // eighth notes, and a snare on every second beat
+    fn groove(bpm: f64, secs: f64) -> Vec<f32> {
+        let sixteenth = 60.0 / bpm / 4.0 * FRAME_RATE as f64;
+        let mut noise = 1u32;
+        pulse(bpm, secs)
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                noise = noise.wrapping_mul(1664525).wrapping_add(1013904223);
+                let position = i as f64 / sixteenth;
+                let t = (position.fract() * sixteenth) as f32 / FRAME_RATE as f32;
+                let accent = [0.25, 0.15][position as usize % 2];
+                let noise = noise as f32 / u32::MAX as f32 * 2.0 - 1.0;
+                let hat = noise * (-t * 120.0).exp();
+                let beat_t =
+                    ((position / 4.0).fract() * 4.0 * sixteenth) as f32 / FRAME_RATE as f32;
+                let snare = if (position / 4.0) as usize % 2 == 1 {
+                    noise * 0.5 * (-beat_t * 25.0).exp()
+                } else {
+                    0.0
+                };
+                sample * 0.8 + hat * accent + snare
+            })
+            .collect()
+    }
+
+    fn tempo_of(samples: &[f32], range: BpmRange) -> Option<Tempo> {
+        let mut analyser = TempoAnalyser::new(FRAME_RATE, range);
+        for block in samples.chunks(4096) {
+            analyser.add_planar_samples(block, 1);
+        }
+        analyser.finish()
+    }
+
+    #[test]
+    fn pulses_in_the_default_range() {
+        // 174 is the drum & bass tempo that used to be halved
+        for bpm in [90.0, 128.0, 174.0] {
+            let tempo = tempo_of(&pulse(bpm, 20.0), BpmRange::default()).unwrap();
+            assert!(
+                (tempo.bpm - bpm).abs() < 0.5,
+                "{} detected as {}",
+                bpm,
+                tempo.bpm
+            );
+            assert!(tempo.confidence > 0.5);
+        }
+    }
+
+    #[test]
+    fn grooves_with_sixteenth_hats() {
+        // the hats repeat at every multiple of a sixteenth note, the beat only at multiples
+        // of four of them
+        for bpm in [124.0, 128.0] {
+            let tempo = tempo_of(&groove(bpm, 20.0), BpmRange::default()).unwrap();
+            assert!(
+                (tempo.bpm - bpm).abs() < 0.5,
+                "{} detected as {}",
+                bpm,
+                tempo.bpm
+            );
+        }
+    }
+
+    #[test]
+    fn tempos_are_folded_into_the_range() {
+        let range = BpmRange {
+            min: 60.0,
+            max: 120.0,
+        };
+        let tempo = tempo_of(&pulse(174.0, 20.0), range).unwrap();
+        assert!((tempo.bpm - 87.0).abs() < 0.5, "detected {}", tempo.bpm);
+    }
+
+    #[test]
+    fn sustained_tone_has_no_tempo() {
+        let tone: Vec<f32> = (0..FRAME_RATE as usize * 20)
+            .map(|i| (2.0 * std::f32::consts::PI * 220.0 * i as f32 / FRAME_RATE as f32).sin())
+            .collect();
+        assert_eq!(tempo_of(&tone, BpmRange::default()), None);
+        assert_eq!(tempo_of(&pulse(120.0, 1.0), BpmRange::default()), None);
+    }
+
+    #[test]
+    fn sustained_chord_has_no_tempo() {
+        // the partials of an A minor chord beat against each other, but not at any tempo
+        let chord: Vec<f32> = (0..FRAME_RATE as usize * 20)
+            .map(|i| {
+                let t = i as f32 / FRAME_RATE as f32;
+                [110.0, 220.0, 261.63, 329.63]
+                    .iter()
+                    .map(|frequency| 0.1 * (2.0 * std::f32::consts::PI * frequency * t).sin())
+                    .sum::<f32>()
+            })
+            .collect();
+        assert_eq!(tempo_of(&chord, BpmRange::default()), None);
+    }
+
+    #[test]
+    fn steady_kick_grid() {
+        // a kick on every beat, louder on the second beat of every bar
+        let period = 60.0 / 128.0;
+        let samples: Vec<f32> = pulse(128.0, 20.0)
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                let beat = (i as f64 / FRAME_RATE as f64 / period) as usize;
+                sample * if beat % 4 == 1 { 1.0 } else { 0.4 }
+            })
+            .collect();
+        let mut analyser = TempoAnalyser::new(FRAME_RATE, BpmRange::default());
+        analyser.add_planar_samples(&samples, 1);
+        let tempo = analyser.finish().unwrap();
+        let grid = analyser.beat_grid(&tempo).unwrap();
+
+        assert_eq!(grid.anchors.len(), 1, "{:?}", grid.anchors);
+        assert!(
+            (grid.anchors[0].bpm - 128.0).abs() < 0.5,
+            "{:?}",
+            grid.anchors
+        );
+        assert!(grid.beat_count >= 40, "{} beats", grid.beat_count);
+        for beat in 0..grid.beat_count {
+            let secs = grid.beat_secs(beat);
+            let kick = (secs / period).round();
+            assert!(
+                (secs - kick * period).abs() < 0.03,
+                "beat {} at {}",
+                beat,
+                secs
+            );
+            assert_eq!(
+                grid.is_downbeat(beat),
+                kick as usize % 4 == 1,
+                "beat {}",
+                beat
+            );
+        }
+    }
+
+    #[test]
+    fn weak_tempos_have_no_grid() {
+        let mut analyser = TempoAnalyser::new(FRAME_RATE, BpmRange::default());
+        analyser.add_planar_samples(&pulse(128.0, 20.0), 1);
+        let tempo = Tempo {
+            bpm: 128.0,
+            confidence: MIN_GRID_CONFIDENCE / 2.0,
+        };
+        assert_eq!(analyser.beat_grid(&tempo), None);
+    }
+
+    #[test]
+    fn anchors() {
+        let beats = |bpm: f64, count: usize| -> Vec<f64> {
+            (0..count).map(|beat| beat as f64 * 60.0 / bpm).collect()
+        };
+
+        // a misplaced first beat doesn't start an anchor of its own
+        let mut late_start = beats(128.0, 32);
+        late_start[0] += 0.035;
+        let anchors = fit_anchors(&late_start, 128.0);
+        assert_eq!(anchors.len(), 1, "{:?}", anchors);
+        assert_eq!((anchors[0].secs, anchors[0].bpm), (0.0, 128.0));
+
+        // a tempo change starts a new anchor
+        let mut change = beats(120.0, 16);
+        change.extend(
+            beats(126.0, 16)
+                .iter()
+                .map(|secs| secs + 7.5 + 60.0 / 126.0),
+        );
+        let anchors = fit_anchors(&change, 126.0);
+        assert_eq!(
+            anchors
+                .iter()
+                .map(|anchor| (anchor.beat, anchor.bpm))
+                .collect::<Vec<_>>(),
+            vec![(0, 120.0), (16, 126.0)]
+        );
+
+        // anchors far off the tempo are pulled into the tolerance around it
+        let anchors = fit_anchors(&beats(160.0, 32), 128.0);
+        assert_eq!(anchors.len(), 1, "{:?}", anchors);
+        assert!((anchors[0].bpm - 128.0 * (1.0 + ANCHOR_BPM_TOLERANCE)).abs() < 0.01);
+    }
+
+    #[test]
+    fn bpm_ranges() {
+        assert_eq!(
+            "100-200".parse(),
+            Ok(BpmRange {
+                min: 100.0,
+                max: 200.0
+            })
+        );
+        assert!("100-150".parse::<BpmRange>().is_err());
+        assert!("fast".parse::<BpmRange>().is_err());
+    }
+}
@@ -0,0 +1,646 @@
+// Broadcast WAV and RIFF INFO metadata
+// -----------------------------------
+//
+// Symphonia only hands us the audio, so WAV files get a second pass: `bwavfile` reads
+// the `bext` chunk, and the `iXML` and `LIST`/`INFO` chunks (which `bwavfile` does not
+// expose, or only once the whole file passes its checks) are read by walking the RIFF
+// chunks ourselves. Each chunk is read on its own, so a missing or unreadable one only
+// leaves its own fields empty.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use bwavfile::WaveReader;
+
+use crate::SongKey;
+
+// Everything we could find in the chunks of a WAV file, empty where a chunk is missing
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WavMetadata {
+    // INFO IART
+    pub artist: String,
+    // INFO INAM, or the iXML scene and take of field recordings
+    pub title: String,
+    // bext description, or the iXML note or INFO ICMT
+    pub description: String,
+    // bext originator (the recorder or DAW that created the file)
+    pub originator: String,
+    // bext origination date and time, like "2023-05-14 18:02:11"
+    pub origination: String,
+    // bext time reference converted from samples since midnight to seconds
+    pub time_reference_secs: Option<f64>,
+    // A key already stored in the iXML INITIALKEY or KEY element
+    pub key: Option<SongKey>,
+}
+
+// Reads the BWF and RIFF INFO metadata of a WAV (or RF64) file
+pub fn read_wav_metadata(path: &str) -> Result<WavMetadata, String> {
+    let mut file =
+        BufReader::new(File::open(path).map_err(|e| format!("cannot open WAV file: {}", e))?);
+    let (chunks, _) =
+        riff_chunks(&mut file).map_err(|e| format!("cannot read WAV chunks: {}", e))?;
+
+    let mut meta = WavMetadata::default();
+
+    let info = info_list(&mut file, &chunks);
+    meta.artist = info_text(&info, b"IART");
+    meta.title = info_text(&info, b"INAM");
+    meta.description = info_text(&info, b"ICMT");
+
+    let ixml = chunks
+        .iter()
+        .find(|chunk| &chunk.id == b"iXML")
+        .and_then(|chunk| read_chunk(&mut file, chunk).ok())
+        .map(|contents| {
+            String::from_utf8_lossy(&contents)
+                .trim_end_matches('\0')
+                .to_string()
+        })
+        .unwrap_or_default();
+
+    if let Some(note) = xml_element(&ixml, "NOTE") {
+        meta.description = note;
+    }
+    if meta.title.is_empty() {
+        meta.title = match (xml_element(&ixml, "SCENE"), xml_element(&ixml, "TAKE")) {
+            (Some(scene), Some(take)) => format!("{} take {}", scene, take),
+            (Some(scene), None) => scene,
+            _ => String::new(),
+        };
+    }
+    meta.key = ["INITIALKEY", "KEY"]
+        .iter()
+        .filter_map(|name| xml_element(&ixml, name))
+        .find_map(|key| key.parse().ok());
+
+    // a file bwavfile rejects has no bext chunk we can use
+    let bext = WaveReader::open(path).ok().and_then(|mut reader| {
+        let sample_rate = reader.format().map(|format| format.sample_rate).ok();
+        Some((reader.broadcast_extension().ok()??, sample_rate))
+    });
+    if let Some((bext, sample_rate)) = bext {
+        if !bext.description.trim().is_empty() {
+            meta.description = bext.description.trim().to_string();
+        }
+        meta.originator = bext.originator.trim().to_string();
+        meta.origination = format!(
+            "{} {}",
+            bext.origination_date.trim(),
+            bext.origination_time.trim()
+        )
+        .trim()
+        .to_string();
+        if let Some(sample_rate) = sample_rate.filter(|sample_rate| *sample_rate > 0) {
+            meta.time_reference_secs = Some(bext.time_reference as f64 / sample_rate as f64);
+        }
+    }
+
+    Ok(meta)
+}
+
+// Stores the key in the `<INITIALKEY>` element of the iXML chunk, adding the chunk if the
+// file has none, and returns the key that was there before. The other chunks (the audio
+// included) are copied byte for byte into a new file that then replaces the original.
+pub fn write_ixml_key(path: &str, key: &str, dry_run: bool) -> Result<Option<String>, String> {
+    let mut file = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header).map_err(|e| e.to_string())?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(String::from(
+            "only RIFF WAVE files can have their iXML updated",
+        ));
+    }
+
+    // the copy only has the chunks we found, so they have to cover the whole file
+    let (chunks, complete) = riff_chunks(&mut file).map_err(|e| e.to_string())?;
+    if !complete {
+        return Err(String::from(
+            "the chunks don't add up to the file size (truncated, or sizes kept elsewhere like in RF64)",
+        ));
+    }
+    let ixml_chunk = chunks.iter().find(|chunk| &chunk.id == b"iXML");
+    let ixml = match ixml_chunk {
+        Some(chunk) => {
+            let contents = read_chunk(&mut file, chunk).map_err(|e| e.to_string())?;
+            String::from_utf8_lossy(&contents)
+                .trim_end_matches('\0')
+                .to_string()
+        }
+        None => String::new(),
+    };
+
+    let previous_key = xml_element(&ixml, "INITIALKEY");
+    if dry_run {
+        return Ok(previous_key);
+    }
+
+    let ixml = set_xml_element(&ixml, "INITIALKEY", key);
+
+    let temp_path = format!("{}.key-tmp", path);
+    let written = copy_with_ixml(&mut file, &chunks, ixml.as_bytes(), &temp_path)
+        .and_then(|_| std::fs::rename(&temp_path, path));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(format!("cannot rewrite WAV file: {}", e));
+    }
+
+    Ok(previous_key)
+}
+
+// Writes a copy of the RIFF file to `to` with the iXML chunk replaced (or appended)
+fn copy_with_ixml<R: Read + Seek>(
+    from: &mut R,
+    chunks: &[RiffChunk],
+    ixml: &[u8],
+    to: &str,
+) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(to)?);
+
+    // the RIFF size is patched in once we know it
+    out.write_all(b"RIFF\0\0\0\0WAVE")?;
+
+    let write_ixml = |out: &mut BufWriter<File>| -> std::io::Result<()> {
+        out.write_all(b"iXML")?;
+        out.write_all(&(ixml.len() as u32).to_le_bytes())?;
+        out.write_all(ixml)?;
+        if ixml.len() % 2 == 1 {
+            out.write_all(&[0])?;
+        }
+        Ok(())
+    };
+
+    let mut ixml_written = false;
+    for chunk in chunks {
+        if &chunk.id == b"iXML" {
+            if !ixml_written {
+                write_ixml(&mut out)?;
+                ixml_written = true;
+            }
+            continue;
+        }
+
+        // copy the header along with the (padded) contents
+        from.seek(SeekFrom::Start(chunk.start - 8))?;
+        let copied = std::io::copy(&mut from.take(8 + chunk.padded_size()), &mut out)?;
+        if copied != 8 + chunk.padded_size() {
+            // an odd sized last chunk may lack its padding byte
+            out.write_all(&[0])?;
+        }
+    }
+    if !ixml_written {
+        write_ixml(&mut out)?;
+    }
+
+    let mut out = out.into_inner().map_err(|e| e.into_error())?;
+    let riff_size = out.stream_position()? - 8;
+    out.seek(SeekFrom::Start(4))?;
+    out.write_all(&(riff_size as u32).to_le_bytes())?;
+    out.sync_all()
+}
+
+// A chunk of a RIFF file: its id, where its contents start and their size
+struct RiffChunk {
+    id: [u8; 4],
+    start: u64,
+    size: u32,
+}
+
+impl RiffChunk {
+    // The size of the chunk in the file, chunks are padded to an even size
+    fn padded_size(&self) -> u64 {
+        self.size as u64 + (self.size as u64 & 1)
+    }
+}
+
+// Lists the top-level chunks of a RIFF file, and whether they cover the whole file. RF64
+// stores the real size of large chunks in the ds64 chunk, so the list stops early at the
+// first chunk with a placeholder size, as it does at a truncated chunk.
+fn riff_chunks<R: Read + Seek>(file: &mut R) -> std::io::Result<(Vec<RiffChunk>, bool)> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(12))?;
+
+    let mut chunks = vec![];
+    loop {
+        // the last chunk may lack its padding byte
+        let position = file.stream_position()?;
+        if position >= file_len {
+            return Ok((chunks, position <= file_len + 1));
+        }
+
+        let mut chunk_header = [0u8; 8];
+        if file.read_exact(&mut chunk_header).is_err() {
+            return Ok((chunks, false));
+        }
+        let id = [
+            chunk_header[0],
+            chunk_header[1],
+            chunk_header[2],
+            chunk_header[3],
+        ];
+        let size = u32::from_le_bytes([
+            chunk_header[4],
+            chunk_header[5],
+            chunk_header[6],
+            chunk_header[7],
+        ]);
+        if size == u32::MAX {
+            return Ok((chunks, false));
+        }
+
+        let chunk = RiffChunk {
+            id,
+            start: file.stream_position()?,
+            size,
+        };
+        if chunk.start + size as u64 > file_len {
+            return Ok((chunks, false));
+        }
+        file.seek(SeekFrom::Current(chunk.padded_size() as i64))?;
+        chunks.push(chunk);
+    }
+}
+
+// Reads the contents of a chunk
+fn read_chunk<R: Read + Seek>(file: &mut R, chunk: &RiffChunk) -> std::io::Result<Vec<u8>> {
+    let mut contents = vec![0u8; chunk.size as usize];
+    file.seek(SeekFrom::Start(chunk.start))?;
+    file.read_exact(&mut contents)?;
+    Ok(contents)
+}
+
+// The sub-chunks of the first LIST/INFO chunk as (id, contents) pairs, or nothing if
+// there is no such chunk we can read
+fn info_list<R: Read + Seek>(file: &mut R, chunks: &[RiffChunk]) -> Vec<([u8; 4], Vec<u8>)> {
+    chunks
+        .iter()
+        .filter(|chunk| &chunk.id == b"LIST" && chunk.size >= 4)
+        .filter_map(|chunk| read_chunk(file, chunk).ok())
+        .find(|contents| &contents[0..4] == b"INFO")
+        .map(|contents| info_items(&contents[4..]))
+        .unwrap_or_default()
+}
+
+// Splits the contents of an INFO list into its items
+fn info_items(mut contents: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut items = vec![];
+    while contents.len() >= 8 {
+        let id = [contents[0], contents[1], contents[2], contents[3]];
+        let size =
+            u32::from_le_bytes([contents[4], contents[5], contents[6], contents[7]]) as usize;
+        let end = (8 + size).min(contents.len());
+        items.push((id, contents[8..end].to_vec()));
+        contents = &contents[(end + (size & 1)).min(contents.len())..];
+    }
+    items
+}
+
+// The text of an INFO item without its NUL terminator, or an empty string
+fn info_text(items: &[([u8; 4], Vec<u8>)], id: &[u8; 4]) -> String {
+    items
+        .iter()
+        .find(|(item_id, _)| item_id == id)
+        .map(|(_, contents)| {
+            String::from_utf8_lossy(contents)
+                .trim_end_matches('\0')
+                .trim()
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+// The trimmed text of the first `<name>` element, if it isn't empty. iXML is flat enough
+// that we don't need a real XML parser for the few elements we look at.
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    let text = xml[start..end].trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+// Replaces the text of the first `<name>` element, or adds the element to the BWFXML
+// document (creating a minimal document if there is none)
+fn set_xml_element(xml: &str, name: &str, text: &str) -> String {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let element = format!("{}{}{}", open, text, close);
+
+    if let Some(start) = xml.find(&open) {
+        if let Some(length) = xml[start..].find(&close) {
+            return format!(
+                "{}{}{}",
+                &xml[..start],
+                element,
+                &xml[start + length + close.len()..]
+            );
+        }
+    }
+
+    match xml.find("</BWFXML>") {
+        Some(end) => format!("{}\t{}\n{}", &xml[..end], element, &xml[end..]),
+        None => format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<BWFXML>\n\t<IXML_VERSION>2.10</IXML_VERSION>\n\t{}\n</BWFXML>\n",
+            element
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A RIFF WAVE file with the given chunks, padded to even sizes
+    fn wav_bytes(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WAVE".to_vec();
+        for (id, contents) in chunks {
+            bytes.extend_from_slice(*id);
+            bytes.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(contents);
+            if contents.len() % 2 == 1 {
+                bytes.push(0);
+            }
+        }
+        let riff_size = (bytes.len() - 8) as u32;
+        bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        bytes
+    }
+
+    // The (id, contents) pairs of the chunks of a RIFF WAVE file
+    fn chunk_contents(bytes: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut file = Cursor::new(bytes);
+        let (chunks, complete) = riff_chunks(&mut file).unwrap();
+        assert!(complete);
+        chunks
+            .iter()
+            .map(|chunk| (chunk.id, read_chunk(&mut file, chunk).unwrap()))
+            .collect()
+    }
+
+    // A path in the temporary directory that is removed when dropped
+    struct TempPath(String);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("wavmeta-{}-{}", std::process::id(), name));
+            TempPath(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    const FMT: &[u8] = &[1, 0, 1, 0, 0x44, 0xac, 0, 0, 0x88, 0x58, 1, 0, 2, 0, 16, 0];
+
+    #[test]
+    fn set_xml_element_replaces_the_first_element() {
+        let xml =
+            "<BWFXML>\n\t<INITIALKEY>Am</INITIALKEY>\n\t<INITIALKEY>C</INITIALKEY>\n</BWFXML>\n";
+        assert_eq!(
+            set_xml_element(xml, "INITIALKEY", "8A"),
+            "<BWFXML>\n\t<INITIALKEY>8A</INITIALKEY>\n\t<INITIALKEY>C</INITIALKEY>\n</BWFXML>\n"
+        );
+        assert_eq!(
+            set_xml_element("<BWFXML><KEY></KEY></BWFXML>", "KEY", "Am"),
+            "<BWFXML><KEY>Am</KEY></BWFXML>"
+        );
+    }
+
+    #[test]
+    fn set_xml_element_adds_missing_elements() {
+        assert_eq!(
+            set_xml_element(
+                "<BWFXML>\n\t<NOTE>x</NOTE>\n</BWFXML>\n",
+                "INITIALKEY",
+                "8A"
+            ),
+            "<BWFXML>\n\t<NOTE>x</NOTE>\n\t<INITIALKEY>8A</INITIALKEY>\n</BWFXML>\n"
+        );
+
+        // an unclosed element is left alone rather than cut short
+        let xml = set_xml_element("<BWFXML><INITIALKEY>Am</BWFXML>", "INITIALKEY", "8A");
+        assert!(xml.starts_with("<BWFXML><INITIALKEY>Am\t<INITIALKEY>8A</INITIALKEY>"));
+
+        let xml = set_xml_element("", "INITIALKEY", "8A");
+        assert!(xml.starts_with("<?xml"));
+        assert_eq!(xml_element(&xml, "INITIALKEY").as_deref(), Some("8A"));
+        assert_eq!(xml_element(&xml, "IXML_VERSION").as_deref(), Some("2.10"));
+    }
+
+    #[test]
+    fn copy_with_ixml_keeps_the_other_chunks() {
+        let data: Vec<u8> = (0..=255).collect();
+        let bytes = wav_bytes(&[
+            (b"fmt ", FMT),
+            (b"iXML", b"<BWFXML></BWFXML>"),
+            (b"data", &data),
+            (b"LIST", b"INFOIART\x03\0\0\0ab\0"),
+        ]);
+
+        let to = TempPath::new("copy.wav");
+        let mut from = Cursor::new(&bytes);
+        let (chunks, _) = riff_chunks(&mut from).unwrap();
+        copy_with_ixml(&mut from, &chunks, b"<BWFXML>x</BWFXML>", &to.0).unwrap();
+
+        let copied = std::fs::read(&to.0).unwrap();
+        assert_eq!(
+            u32::from_le_bytes([copied[4], copied[5], copied[6], copied[7]]) as usize,
+            copied.len() - 8
+        );
+        assert_eq!(
+            chunk_contents(&copied),
+            vec![
+                (*b"fmt ", FMT.to_vec()),
+                (*b"iXML", b"<BWFXML>x</BWFXML>".to_vec()),
+                (*b"data", data),
+                (*b"LIST", b"INFOIART\x03\0\0\0ab\0".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn copy_with_ixml_appends_a_missing_chunk() {
+        // an odd sized data chunk, with its padding byte missing at the end of the file
+        let mut bytes = wav_bytes(&[(b"fmt ", FMT), (b"data", &[1, 2, 3])]);
+        bytes.pop();
+
+        let to = TempPath::new("append.wav");
+        let mut from = Cursor::new(&bytes);
+        let (chunks, complete) = riff_chunks(&mut from).unwrap();
+        assert!(complete);
+        copy_with_ixml(&mut from, &chunks, b"<BWFXML/>", &to.0).unwrap();
+
+        assert_eq!(
+            chunk_contents(&std::fs::read(&to.0).unwrap()),
+            vec![
+                (*b"fmt ", FMT.to_vec()),
+                (*b"data", vec![1, 2, 3]),
+                (*b"iXML", b"<BWFXML/>".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_ixml_key_round_trip() {
+        let path = TempPath::new("key.wav");
+        std::fs::write(&path.0, wav_bytes(&[(b"fmt ", FMT), (b"data", &[0; 64])])).unwrap();
+
+        assert_eq!(write_ixml_key(&path.0, "8A", false), Ok(None));
+        assert_eq!(
+            write_ixml_key(&path.0, "9A", false),
+            Ok(Some(String::from("8A")))
+        );
+        assert_eq!(
+            write_ixml_key(&path.0, "10A", true),
+            Ok(Some(String::from("9A")))
+        );
+
+        let chunks = chunk_contents(&std::fs::read(&path.0).unwrap());
+        assert_eq!(chunks[1], (*b"data", vec![0; 64]));
+        let ixml = String::from_utf8(chunks[2].1.clone()).unwrap();
+        assert_eq!(xml_element(&ixml, "INITIALKEY").as_deref(), Some("9A"));
+    }
+
+    #[test]
+    fn write_ixml_key_leaves_incomplete_files_alone() {
+        // a data chunk with an RF64 style placeholder size, and one running past the end
+        let mut placeholder = wav_bytes(&[(b"fmt ", FMT), (b"data", &[0; 64])]);
+        placeholder[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut truncated = wav_bytes(&[(b"fmt ", FMT), (b"data", &[0; 64])]);
+        truncated.truncate(truncated.len() - 10);
+
+        for bytes in [placeholder, truncated] {
+            let path = TempPath::new("incomplete.wav");
+            std::fs::write(&path.0, &bytes).unwrap();
+            assert!(write_ixml_key(&path.0, "8A", false).is_err());
+            assert_eq!(std::fs::read(&path.0).unwrap(), bytes);
+        }
+    }
+
+    // A bext chunk with the given description, originator and time reference
+    fn bext_chunk(description: &str, originator: &str, time_reference: u64) -> Vec<u8> {
+        let text = |text: &str, size: usize| {
+            let mut field = text.as_bytes().to_vec();
+            field.resize(size, 0);
+            field
+        };
+        let mut bext = text(description, 256);
+        bext.extend(text(originator, 32));
+        bext.extend(text("", 32));
+        bext.extend(text("2023-05-14", 10));
+        bext.extend(text("18:02:11", 8));
+        bext.extend(time_reference.to_le_bytes());
+        bext.extend(text("", 2 + 64 + 10 + 180));
+        bext
+    }
+
+    // Reads the metadata of a WAV file with the given chunks
+    fn metadata(name: &str, chunks: &[(&[u8; 4], &[u8])]) -> Result<WavMetadata, String> {
+        let path = TempPath::new(name);
+        std::fs::write(&path.0, wav_bytes(chunks)).unwrap();
+        read_wav_metadata(&path.0)
+    }
+
+    #[test]
+    fn info_items_are_split_on_their_padded_sizes() {
+        assert_eq!(
+            info_items(b"IART\x03\0\0\0ab\0\0INAM\x02\0\0\0cd"),
+            vec![(*b"IART", b"ab\0".to_vec()), (*b"INAM", b"cd".to_vec())]
+        );
+
+        // an item running past the end is cut short, a partial header is ignored
+        assert_eq!(
+            info_items(b"ICMT\x10\0\0\0abc"),
+            vec![(*b"ICMT", b"abc".to_vec())]
+        );
+        assert_eq!(info_items(b"IART\x01\0"), vec![]);
+    }
+
+    #[test]
+    fn info_without_ixml_or_bext() {
+        let info = b"INFOIART\x07\0\0\0Artist\0\0INAM\x06\0\0\0Title\0IKEY\x08\0\0\0FM; AM\0\0";
+        let meta = metadata(
+            "info.wav",
+            &[(b"fmt ", FMT), (b"LIST", info), (b"data", &[0; 64])],
+        )
+        .unwrap();
+        assert_eq!(
+            meta,
+            WavMetadata {
+                artist: String::from("Artist"),
+                title: String::from("Title"),
+                // IKEY holds keywords, not a musical key
+                key: None,
+                ..WavMetadata::default()
+            }
+        );
+    }
+
+    #[test]
+    fn ixml_and_bext() {
+        let ixml = b"<BWFXML><SCENE>12</SCENE><TAKE>3</TAKE><NOTE>wind</NOTE><KEY>x</KEY><INITIALKEY>8A</INITIALKEY></BWFXML>";
+        let bext = bext_chunk("", "Recorder", 44100 * 90);
+        let meta = metadata(
+            "bwf.wav",
+            &[
+                (b"fmt ", FMT),
+                (b"bext", &bext),
+                (b"iXML", ixml),
+                (b"data", &[0; 64]),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            meta,
+            WavMetadata {
+                title: String::from("12 take 3"),
+                description: String::from("wind"),
+                originator: String::from("Recorder"),
+                origination: String::from("2023-05-14 18:02:11"),
+                time_reference_secs: Some(90.0),
+                key: Some(SongKey::AMin),
+                ..WavMetadata::default()
+            }
+        );
+
+        // the bext description wins over the iXML note
+        let bext = bext_chunk("Interview", "", 0);
+        let meta = metadata(
+            "bwf-description.wav",
+            &[
+                (b"fmt ", FMT),
+                (b"bext", &bext),
+                (b"iXML", ixml),
+                (b"data", &[0; 64]),
+            ],
+        )
+        .unwrap();
+        assert_eq!(meta.description, "Interview");
+    }
+
+    #[test]
+    fn files_bwavfile_rejects_keep_their_info() {
+        // no fmt chunk
+        let info = b"INFOIART\x02\0\0\0A\0";
+        let ixml = b"<BWFXML><INITIALKEY>Am</INITIALKEY></BWFXML>";
+        let meta = metadata(
+            "no-fmt.wav",
+            &[(b"LIST", info), (b"iXML", ixml), (b"data", &[0; 64])],
+        )
+        .unwrap();
+        assert_eq!(meta.artist, "A");
+        assert_eq!(meta.key, Some(SongKey::AMin));
+
+        assert!(read_wav_metadata("/nonexistent/file.wav").is_err());
+    }
+}